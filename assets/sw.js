@@ -6,6 +6,7 @@ const filesToCache = [
 	'./',
 	'./index.html',
 	'./initializer.js',
+	'./worker.js',
 	'./walkers-editor.js',
 	'./walkers-editor_bg.wasm',
 	'./apple-touch-icon.png',
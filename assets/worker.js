@@ -0,0 +1,14 @@
+// Web Worker running the network requests and the decoding of responses, see src/app/worker/web.rs.
+
+import init, { worker_main } from "./walkers-editor.js";
+
+// messages sent while the module is loading are handled once it is initialized
+const queued = [];
+self.onmessage = (event) => queued.push(event);
+
+await init();
+worker_main();
+
+for (const event of queued) {
+	self.onmessage(event);
+}
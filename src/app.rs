@@ -7,26 +7,42 @@ mod osmchange;
 mod worker;
 pub mod icons;
 
-use editor::{consts::*, states::*, visual::FillMode};
+use editor::{cache::{Change, Element, ElementId}, consts::*, merge::Conflict, states::*, visual::FillMode};
 use eframe::egui;
 use egui::containers::menu::{MenuButton, MenuConfig};
 use egui::{AtomExt, Button, CentralPanel, Color32, Context, Frame, Image, Margin, PopupCloseBehavior, RichText, ThemePreference, TopBottomPanel, Ui, Vec2};
-use osm::{OsmClient, TargetServer};
+use osm::{Account, Accounts, AuthCode, OsmApiError, OsmClient, OsmResult, TargetServer, Tile};
+use std::collections::HashMap;
 use osmchange::OsmChange;
 use providers::{providers, Provider};
 use walkers::{Map, Tiles};
-use windows::Window;
-use worker::{Request, Response, Worker, WorkerHandle};
+use windows::{HistoryResult, ToolbarResult, Window};
+use worker::{Request, RequestId, Response, WorkerHandle};
 
-#[derive(Default)]
 pub struct AppState {
 	pub view: View,
 	pub target_server_ui: TargetServer,
+	pub servers: Vec<TargetServer>, // builtin and user-defined server profiles
+	pub server_form: ServerForm,
 	pub show_licenses_modal: bool,
 	#[cfg(target_family = "wasm")]
 	pub show_firefox_modal: bool,
 }
 
+impl Default for AppState {
+	fn default() -> Self {
+		Self {
+			view: View::default(),
+			target_server_ui: TargetServer::default(),
+			servers: TargetServer::builtin().to_vec(),
+			server_form: ServerForm::default(),
+			show_licenses_modal: false,
+			#[cfg(target_family = "wasm")]
+			show_firefox_modal: false,
+		}
+	}
+}
+
 #[derive(Default, PartialEq, Eq)]
 pub enum View {
 	#[default]
@@ -74,7 +90,8 @@ impl MyApp {
 								self.uploader.osmchange_text = self.uploader.osmchange.to_string_pretty().unwrap();
 							}
 
-							let btn = title_bar_button("Auth", prepare_icon(ctx, icons::USER, TOP_BAR_ICON_SIZE));
+							let account = self.active_account();
+							let btn = title_bar_button(account.map_or("Auth", |x| x.user.display_name.as_str()), prepare_icon(ctx, icons::USER, TOP_BAR_ICON_SIZE));
 							if ui.add_enabled(self.state.view != View::Auth, btn).clicked() {
 								self.state.view = View::Auth;
 							}
@@ -108,7 +125,16 @@ impl MyApp {
 					self.editor.osm_data.refresh_in_view_flag = true;
 				}
 
-				if ctx.input_mut(|i| i.consume_shortcut(shortcuts::WIREFRAME)) {
+				// Ctrl+Z also matches Ctrl+Shift+Z, so redo is checked first
+				if !ctx.wants_keyboard_input() {
+					if ctx.input_mut(|i| i.consume_shortcut(shortcuts::REDO)) {
+						self.editor.redo();
+					} else if ctx.input_mut(|i| i.consume_shortcut(shortcuts::UNDO)) {
+						self.editor.undo();
+					}
+				}
+
+				if !ctx.wants_keyboard_input() && ctx.input_mut(|i| i.consume_shortcut(shortcuts::WIREFRAME)) {
 					// todo: avoid refreshing the mesh cache if fill mode isnt partial
 					self.editor.map_state.selected_fill_mode = match self.editor.map_state.selected_fill_mode {
 						FillMode::Wireframe => FillMode::Partial,
@@ -137,14 +163,34 @@ impl MyApp {
 					}
 
 					if self.editor.window_flags & Window::Tags as u8 == 0 {
-						if let Some(element) = self.editor.plugin_state.selected.as_ref().or_else(|| self.editor.plugin_state.hovered.first()) {
-							let element = self.editor.osm_data.get(element.id_ref()).expect("id not found");
-							windows::tags(ui, element.tags());
+						if let Some((id, tags)) = self.editor.tag_editor.take_pending(self.editor.plugin_state.selected.as_ref())
+							&& let Some(element) = self.editor.osm_data.element(&id)
+						{
+							self.editor.osm_data.apply_change(Change::update_tags(&element.element_ref(), tags));
+							self.editor.map_state.record_used_provider();
+						}
+
+						// only the selected element can be edited, hovered ones are just shown
+						if let Some(selected) = &self.editor.plugin_state.selected {
+							let element = self.editor.osm_data.get(selected.id_ref()).expect("id not found");
+							if let Some(tags) = windows::tags(ui, &element, Some(&mut self.editor.tag_editor)) {
+								let change = Change::update_tags(&element, tags);
+								self.editor.osm_data.apply_change(change);
+								self.editor.map_state.record_used_provider();
+							}
+						} else if let Some(hovered) = self.editor.plugin_state.hovered.first() {
+							let element = self.editor.osm_data.get(hovered.id_ref()).expect("id not found");
+							windows::tags(ui, &element, None);
 						}
 					}
 
 					if self.editor.window_flags & Window::History as u8 == 0 {
-						windows::history(ui, &self.editor.osm_data.changes);
+						let osm = &self.editor.osm_data;
+						match windows::history(ui, &osm.changes, &osm.undone, osm.conflicts.len()) {
+							HistoryResult::Undo => self.editor.undo(),
+							HistoryResult::Redo => self.editor.redo(),
+							HistoryResult::None => {},
+						}
 					}
 
 					if self.editor.window_flags & Window::Map as u8 == 0 {
@@ -160,11 +206,27 @@ impl MyApp {
 						}
 					}
 
-					if self.editor.window_flags & Window::Toolbar as u8 == 0 && windows::toolbar(ui, &mut self.editor.map_state, &self.editor.plugin_state.map_bbox) {
-						let request = Request::GetMap(Box::new(self.editor.plugin_state.map_bbox.clone()));
-						self.worker_handle.send_message(request);
+					let toolbar = if self.editor.window_flags & Window::Toolbar as u8 == 0 {
+						windows::toolbar(ui, &mut self.editor.map_state, &self.editor.plugin_state.map_bbox)
+					} else { ToolbarResult::None };
+
+					// an explicit download also refreshes tiles that were downloaded before
+					let tiles = match toolbar {
+						ToolbarResult::Download => Some(Tile::covering(&self.editor.plugin_state.map_bbox)),
+						ToolbarResult::CancelDownload => {
+							if let MapDownloadState::Downloading(progress) = &mut self.editor.map_state.download {
+								self.worker_handle.send_message(Request::Cancel(progress.request));
+								progress.cancelled = true;
+							}
+							None
+						}
+						ToolbarResult::None => self.auto_download_tiles(ctx),
+					};
 
-						self.editor.map_state.download = MapDownloadState::Downloading;
+					if let Some(tiles) = tiles {
+						let total_tiles = tiles.len();
+						let id = self.worker_handle.send_message(Request::GetMap(tiles));
+						self.editor.map_state.download = MapDownloadState::Downloading(DownloadProgress::new(id, total_tiles));
 					}
 
 					#[cfg(feature = "debug")] {
@@ -182,7 +244,6 @@ impl MyApp {
 			View::Upload => {
 				CentralPanel::default().show(ctx, |ui| {
 					use egui::ScrollArea;
-					use osmchange::Tag;
 
 					ui.heading("Upload to OpenStreetMap");
 					ui.collapsing("View osmChange", |ui| {
@@ -191,13 +252,35 @@ impl MyApp {
 						});
 					});
 
-					// todo: simple function to check whether authentication exists
-					if self.authenticator.token.get(&self.state.target_server_ui).is_some_and(Result::is_ok) {
+					if let Some(account) = self.active_account() {
+						ui.label(format!("Uploading as {}.", account.user.display_name));
+
 						ui.add_space(10.0);
-						if ui.button("Create Changeset").clicked() {
-							// todo: figure out why tags do not show up on OSM
-							let tags = vec![Tag { k: "created_by".into(), v: crate::USER_AGENT.into() }]; // todo
-							self.worker_handle.send_message(Request::CreateChangeset(tags));
+						windows::changeset_form(ui, &mut self.uploader.form, &self.editor.map_state.used_providers);
+
+						ui.add_space(10.0);
+						let osm = &mut self.editor.osm_data;
+						let conflicts = osm.conflicts.len();
+						if let Some(conflict) = osm.conflicts.first_mut() {
+							ui.label(RichText::new(format!("{conflicts} edited element(s) were changed on the server, please merge them before uploading.")).color(ui.visuals().warn_fg_color));
+							if windows::merge_conflict(ui, conflict) {
+								let conflict = osm.conflicts.remove(0);
+								osm.resolve_conflict(&conflict);
+
+								self.uploader.osmchange = OsmChange::from(&osm.changes);
+								self.uploader.osmchange_text = self.uploader.osmchange.to_string_pretty().unwrap_or_default();
+							}
+							ui.add_space(10.0);
+						}
+
+						let enabled = !self.uploader.upload_pending && !self.uploader.osmchange.is_empty() && self.uploader.form.is_valid() && self.editor.osm_data.conflicts.is_empty();
+						if ui.add_enabled(enabled, Button::new("Upload")).clicked() {
+							let tags = self.uploader.form.tags(&self.editor.map_state.used_providers);
+							let osmchange = OsmChange::from(&self.editor.osm_data.changes);
+							self.worker_handle.send_message(Request::UploadChangeset(tags, Box::new(osmchange)));
+
+							self.uploader.reset_progress();
+							self.uploader.upload_pending = true;
 						}
 
 						if let Some(result) = &self.uploader.changeset_creation {
@@ -205,7 +288,7 @@ impl MyApp {
 								Ok(id) => {
 									ui.horizontal(|ui| {
 										ui.label("Changeset ID: ");
-										ui.hyperlink_to(id.to_string(), format!("https://{}/changeset/{}", self.state.target_server_ui.base_url(), id));
+										ui.hyperlink_to(id.to_string(), format!("{}/changeset/{}", self.state.target_server_ui.base_url(), id));
 									});
 								}
 								Err(err) => {
@@ -213,6 +296,45 @@ impl MyApp {
 								}
 							}
 						}
+
+						if let Some(result) = &self.uploader.changeset_upload {
+							match result {
+								Ok(_) => { ui.label("Uploaded changes."); }
+								Err(err) => {
+									ui.label(RichText::new(format!("Failed to upload changes:\n{err}")).color(ui.visuals().error_fg_color));
+								}
+							}
+						}
+
+						if let Some(result) = &self.uploader.changeset_close {
+							match result {
+								Ok(_) => { ui.label("Closed changeset."); }
+								Err(err) => {
+									ui.label(RichText::new(format!("Failed to close changeset:\n{err}")).color(ui.visuals().error_fg_color));
+								}
+							}
+						}
+
+						if self.uploader.upload_pending {
+							ui.spinner();
+						}
+
+						match &mut self.uploader.conflict {
+							Some(Ok(conflict)) => {
+								ui.add_space(10.0);
+								if windows::merge_conflict(ui, conflict) {
+									self.editor.osm_data.resolve_conflict(conflict);
+									self.uploader.conflict = None;
+
+									self.uploader.osmchange = OsmChange::from(&self.editor.osm_data.changes);
+									self.uploader.osmchange_text = self.uploader.osmchange.to_string_pretty().unwrap_or_default();
+								}
+							}
+							Some(Err(err)) => {
+								ui.label(RichText::new(format!("Failed to fetch the conflicting element:\n{err}")).color(ui.visuals().error_fg_color));
+							}
+							None => {},
+						}
 					} else {
 						ui.strong("Please authenticate to OSM using the Auth tab.");
 					}
@@ -220,124 +342,347 @@ impl MyApp {
 			}
 			View::Auth => {
 				CentralPanel::default().show(ctx, |ui| {
-					use egui::TextEdit;
-
 					ui.heading("Authenticate to OpenStreetMap");
 
-					let prev_server = self.state.target_server_ui;
-					server_selector(ui, &mut self.state.target_server_ui);
+					let prev_server = self.state.target_server_ui.clone();
+					server_selector(ui, &mut self.state.target_server_ui, &mut self.state.servers);
 					if prev_server != self.state.target_server_ui {
 						// update target server for OsmClient of worker
-						self.worker_handle.send_message(Request::SetTargetServer(self.state.target_server_ui));
+						self.worker_handle.send_message(Request::SetTargetServer(self.state.target_server_ui.clone()));
+						if let MapDownloadState::Downloading(progress) = &self.editor.map_state.download {
+							self.worker_handle.send_message(Request::Cancel(progress.request));
+						}
+						self.editor.clear_osm_data();
+						self.validate_accounts();
 					}
 
+					ui.collapsing("Add server", |ui| {
+						if windows::server_form(ui, &mut self.state.server_form)
+							&& let Some(server) = self.state.server_form.target_server()
+						{
+							self.state.servers.push(server);
+							self.state.server_form = ServerForm::default();
+						}
+					});
+
 					ui.add_space(10.0);
 
-					if self.state.target_server_ui == TargetServer::OpenStreetMap {
+					let base_url = self.state.target_server_ui.base_url();
+
+					if self.state.target_server_ui == TargetServer::openstreetmap() {
 						ui.strong(format!("The main OpenStreetMap instance is not available for editing in {} as of now.", env!("CARGO_PKG_NAME")));
 					} else {
-						ui.label("1. Open this URL and follow the authorization process:");
-						ui.hyperlink(osm::client_auth_url(self.state.target_server_ui));
+						if let Some(accounts) = self.authenticator.accounts.get_mut(&base_url)
+							&& let Some(active) = accounts.active
+						{
+							if account_selector(ui, accounts) {
+								self.worker_handle.send_message(Request::SetActiveAccount(accounts.active.unwrap_or(active)));
+							}
 
-						ui.add_space(10.0);
-						ui.label("2. Paste the resulting code into the field below:");
-						let widget = TextEdit::singleline(&mut self.authenticator.authorization_code);
-						if ui.add_enabled(!self.authenticator.request_pending, widget).lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
-							self.worker_handle.send_message(Request::FetchToken(self.authenticator.authorization_code.clone()));
-							self.authenticator.request_pending = true;
+							if ui.button("Log out").clicked() {
+								self.worker_handle.send_message(Request::Logout(active));
+								accounts.remove(active);
+							}
+
+							ui.add_space(10.0);
+							ui.collapsing("Add account", |ui| {
+								ui.label("The browser logs in with the account of the website, log out there first to add another one.");
+								ui.add_space(10.0);
+								self.login(ui);
+							});
+						} else {
+							self.login(ui);
 						}
 
-						// todo: ui should change based on the result of the authentication
-						// todo: logout button
+						if let Some(err) = self.authenticator.error.get(&base_url) {
+							ui.label(RichText::new(err.to_string()).color(ui.visuals().error_fg_color));
+						}
 					}
 				});
 			}
 		}
 	}
+
+	fn login(&mut self, ui: &mut Ui) {
+		#[cfg(not(target_family = "wasm"))] {
+			if ui.add_enabled(!self.authenticator.request_pending, Button::new("Log in with browser")).clicked() {
+				self.worker_handle.send_message(Request::Authorize);
+				self.authenticator.request_pending = true;
+			}
+
+			if let Some(url) = &self.authenticator.authorize_url {
+				ui.horizontal(|ui| {
+					ui.spinner();
+					ui.label("Waiting for the browser, if it did not open, use");
+					ui.hyperlink_to("this link", url);
+				});
+			}
+
+			ui.add_space(10.0);
+			ui.collapsing("Enter code manually", |ui| {
+				self.out_of_band_auth(ui);
+			});
+		}
+
+		#[cfg(target_family = "wasm")]
+		self.out_of_band_auth(ui);
+	}
+
+	// The server displays the code after the authorization, which is pasted by the user.
+	fn out_of_band_auth(&mut self, ui: &mut Ui) {
+		use egui::TextEdit;
+
+		ui.label("1. Open this URL and follow the authorization process:");
+		ui.hyperlink(osm::client_auth_url(&self.state.target_server_ui));
+
+		ui.add_space(10.0);
+		ui.label("2. Paste the resulting code into the field below:");
+		let widget = TextEdit::singleline(&mut self.authenticator.authorization_code);
+		if ui.add_enabled(!self.authenticator.request_pending, widget).lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+			self.worker_handle.send_message(Request::FetchToken(AuthCode::out_of_band(&self.authenticator.authorization_code)));
+			self.authenticator.request_pending = true;
+		}
+	}
 }
 
 impl MyApp {
 	pub fn new(cc: &eframe::CreationContext) -> Self {
-		#[cfg(not(target_family = "wasm"))]
-		use crossbeam_channel as channel;
-		#[cfg(target_family = "wasm")]
-		use futures::channel::mpsc as channel;
-
 		cc.egui_ctx.options_mut(|x| x.theme_preference = ThemePreference::Dark);
 		egui_extras::install_image_loaders(&cc.egui_ctx);
 
-		let (request_sender, request_receiver) = channel::unbounded::<Request>();
-		let (response_sender, response_receiver) = channel::unbounded::<Response>();
+		let mut state = AppState::default();
+		let mut authenticator = AuthenticatorState::default();
+		let mut osm_client = OsmClient::new(TargetServer::default());
 
-		let mut worker = Worker {
-			osm_client: OsmClient::new(TargetServer::default()),
-			sender: response_sender,
-		};
+		if let Some(storage) = cc.storage {
+			if let Some(servers) = eframe::get_value::<Vec<TargetServer>>(storage, STORAGE_SERVERS) {
+				state.servers.extend(servers);
+			}
+			if let Some(server) = eframe::get_value::<TargetServer>(storage, STORAGE_TARGET_SERVER) {
+				osm_client.set_target_server(server.clone());
+				state.target_server_ui = server;
+			}
+			if let Some(accounts) = eframe::get_value::<HashMap<String, Accounts>>(storage, STORAGE_ACCOUNTS) {
+				authenticator.accounts.clone_from(&accounts);
+				osm_client.accounts = accounts;
+			}
+		}
 
-		#[cfg(not(target_family = "wasm"))]
-		let worker_handle = WorkerHandle {
-			thread: std::thread::spawn(move || worker.run(request_receiver)),
-			sender: request_sender,
-			receiver: response_receiver,
-		};
+		let worker_handle = WorkerHandle::spawn(osm_client);
 
-		#[cfg(target_family = "wasm")]
-		wasm_bindgen_futures::spawn_local(async move {
-			worker.run(request_receiver).await;
-		});
+		#[cfg(target_family = "wasm")] {
+			state.show_firefox_modal = cc.integration_info.web_info.user_agent.to_lowercase().contains("firefox");
+		}
 
-		#[cfg(target_family = "wasm")]
-		let worker_handle = WorkerHandle {
-			sender: request_sender,
-			receiver: response_receiver,
+		let mut app = Self {
+			worker_handle, state,
+			editor: EditorState::new(providers(&cc.egui_ctx)),
+			uploader: UploaderState::default(),
+			authenticator,
 		};
 
-		#[cfg(target_family = "wasm")]
-		let state = AppState {
-			show_firefox_modal: cc.integration_info.web_info.user_agent.to_lowercase().contains("firefox"),
-			..Default::default()
-		};
+		// stored tokens may have expired or been revoked in the meantime
+		app.validate_accounts();
+		app
+	}
 
-		#[cfg(not(target_family = "wasm"))]
-		let state = AppState::default();
+	// The account used for all authenticated requests to the target server.
+	fn active_account(&self) -> Option<&Account> {
+		self.authenticator.accounts.get(&self.state.target_server_ui.base_url()).and_then(Accounts::active)
+	}
 
-		Self {
-			worker_handle, state,
-			editor: EditorState::new(providers(&cc.egui_ctx)),
-			uploader: UploaderState::default(),
-			authenticator: AuthenticatorState::default(),
+	// Forgets the active account of the target server if its token was rejected, so the user is asked to log in again.
+	fn check_auth<T>(&mut self, result: &OsmResult<T>) {
+		if let Err(OsmApiError::AuthExpired) = result {
+			let base_url = self.state.target_server_ui.base_url();
+			if let Some(accounts) = self.authenticator.accounts.get_mut(&base_url)
+				&& let Some(active) = accounts.active
+			{
+				accounts.remove(active);
+				self.worker_handle.send_message(Request::Logout(active));
+			}
+			self.authenticator.error.insert(base_url, OsmApiError::AuthExpired);
+		}
+	}
+
+	// Fetches the users of the target server once, which also checks whether their tokens are still valid.
+	fn validate_accounts(&mut self) {
+		let base_url = self.state.target_server_ui.base_url();
+		if self.authenticator.accounts.get(&base_url).is_some_and(|x| !x.list.is_empty()) && self.authenticator.validated.insert(base_url) {
+			self.worker_handle.send_message(Request::ValidateAccounts);
+		}
+	}
+
+	// Tiles in view that were not downloaded yet, once zoomed in far enough and no other download is running.
+	fn auto_download_tiles(&self, ctx: &Context) -> Option<Vec<Tile>> {
+		let map_state = &self.editor.map_state;
+		if !map_state.auto_download || self.editor.map_memory.zoom() < AUTO_DOWNLOAD_MIN_ZOOM {
+			return None;
+		}
+
+		let MapDownloadState::Idle(status) = &map_state.download else { return None; };
+		if let Some((Err(_), time)) = status && ctx.input(|i| i.time) - time < AUTO_DOWNLOAD_ERROR_PAUSE_SECONDS {
+			return None;
 		}
+
+		let tiles = Tile::covering(&self.editor.plugin_state.map_bbox).into_iter()
+			.filter(|x| !map_state.downloaded_tiles.contains(x))
+			.collect::<Vec<_>>();
+		(!tiles.is_empty() && tiles.len() <= MAX_DOWNLOAD_TILES).then_some(tiles)
 	}
 
-	fn handle_message(&mut self, msg: Response, ctx: &Context) {
+	fn handle_message(&mut self, id: RequestId, msg: Response, ctx: &Context) {
 		match msg {
-			Response::Map(result) => {
-				let result = result.map(|data| {
-					self.editor.osm_data.append_new_nodes_ways(data);
+			Response::MapTile(tile, result) => {
+				// tiles of a download that was dropped, for example by switching the server, belong to other data
+				if self.editor.map_state.download_progress(id).is_none() {
+					return;
+				}
+
+				// tiles are shown as soon as they arrive, errors are reported once all tiles are done
+				if let Ok(data) = result {
+					self.editor.osm_data.merge_downloaded(data);
 					self.editor.osm_data.refresh_in_view_flag = true;
-				});
+					self.editor.map_state.downloaded_tiles.insert(tile);
+				}
 
+				if let Some(progress) = self.editor.map_state.download_progress(id) {
+					progress.tiles += 1;
+					progress.current = None;
+					progress.retry = None;
+				}
+			}
+			Response::MapProgress(current) => {
+				if let Some(progress) = self.editor.map_state.download_progress(id) {
+					progress.current = Some(current);
+					progress.retry = None;
+				}
+			}
+			Response::MapRetry(err, delay) => {
 				let time = ctx.input(|i| i.time);
-				self.editor.map_state.download = MapDownloadState::Idle(Some((result, time)));
+				if let Some(progress) = self.editor.map_state.download_progress(id) {
+					progress.retry = Some((err, time + delay.as_secs_f64()));
+				}
+			}
+			Response::Map(result) => {
+				if self.editor.map_state.download_progress(id).is_some() {
+					let time = ctx.input(|i| i.time);
+					self.editor.map_state.download = MapDownloadState::Idle(Some((result, time)));
+				}
 			},
-			Response::Token(token, target_server) => {
-				self.authenticator.token.insert(target_server, token);
+			#[cfg(not(target_family = "wasm"))]
+			Response::AuthorizeUrl(url) => {
+				ctx.open_url(egui::OpenUrl::new_tab(&url));
+				self.authenticator.authorize_url = Some(url);
+			}
+			#[cfg(not(target_family = "wasm"))]
+			Response::AuthCode(result) => {
+				self.authenticator.authorize_url = None;
+				match result {
+					Ok(auth_code) => { self.worker_handle.send_message(Request::FetchToken(auth_code)); }
+					Err(err) => {
+						self.authenticator.error.insert(self.state.target_server_ui.base_url(), err);
+						self.authenticator.request_pending = false;
+					}
+				}
+			}
+			Response::Account(result, target_server) => {
+				let base_url = target_server.base_url();
+				match result {
+					Ok(account) => {
+						self.authenticator.accounts.entry(base_url.clone()).or_default().insert(account);
+						self.authenticator.error.remove(&base_url);
+					}
+					Err(err) => { self.authenticator.error.insert(base_url, err); }
+				}
+				self.authenticator.authorization_code.clear();
 				self.authenticator.request_pending = false;
 			}
+			Response::AccountValidated(user_id, result, target_server) => {
+				let base_url = target_server.base_url();
+				let Some(accounts) = self.authenticator.accounts.get_mut(&base_url) else { return; };
+				match result {
+					Ok(user) => accounts.update_user(user),
+					Err(err) => {
+						// other errors, like a missing connection, do not tell anything about the token
+						if let OsmApiError::AuthExpired = err {
+							accounts.remove(user_id);
+						}
+						self.authenticator.error.insert(base_url, err);
+					}
+				}
+			}
+			Response::LoggedOut(result, target_server) => {
+				// the account is forgotten either way, but its token may still be valid
+				if let Err(err) = result {
+					self.authenticator.error.entry(target_server.base_url()).or_insert(err);
+				}
+			}
 			Response::CreatedChangeset(result) => {
+				self.check_auth(&result);
+				// nothing else will be reported if the changeset could not be created
+				self.uploader.upload_pending = result.is_ok();
 				self.uploader.changeset_creation = Some(result);
 			}
-			Response::ClosedChangeset(_result) => {
-				todo!();
+			Response::UploadedChangeset(result) => {
+				self.check_auth(&result);
+				if let Ok(diff) = &result {
+					self.editor.osm_data.apply_diff_result(diff);
+
+					if self.editor.osm_data.changes.is_empty() {
+						self.editor.map_state.used_providers.clear();
+					}
+
+					// placeholder ids may have been replaced
+					if self.editor.plugin_state.selected.as_ref().is_some_and(|e| self.editor.osm_data.get(e.id_ref()).is_none()) {
+						self.editor.plugin_state.selected = None;
+					}
+
+					self.uploader.osmchange = OsmChange::from(&self.editor.osm_data.changes);
+					self.uploader.osmchange_text = self.uploader.osmchange.to_string_pretty().unwrap_or_default();
+				}
+
+				self.uploader.changeset_upload = Some(result);
+			}
+			Response::Conflict(conflict, result) => {
+				let osm = &mut self.editor.osm_data;
+				let conflict = result.and_then(|data| {
+					let server = match conflict.element {
+						ElementId::Node(id) => data.nodes.get(&id).cloned().map(Element::Node),
+						ElementId::Way(id) => data.ways.get(&id).cloned().map(Element::Way),
+					}.ok_or_else(|| OsmApiError::parse("conflicting element missing in response"))?;
+
+					let local = osm.element(&conflict.element);
+					let base = osm.base.get(&conflict.element).or(local.as_ref()).unwrap_or(&server).clone();
+
+					// nodes of the server version of a way may not have been downloaded yet
+					osm.append_new_nodes_ways(data);
+
+					Ok(Conflict::new(&base, local, server))
+				});
+
+				self.uploader.conflict = Some(conflict);
+			}
+			Response::ClosedChangeset(result) => {
+				self.uploader.upload_pending = false;
+				self.uploader.changeset_close = Some(result);
 			}
 		}
 	}
 }
 
 impl eframe::App for MyApp {
+	fn save(&mut self, storage: &mut dyn eframe::Storage) {
+		let servers = self.state.servers.iter().filter(|x| !x.is_builtin()).collect::<Vec<_>>();
+
+		eframe::set_value(storage, STORAGE_SERVERS, &servers);
+		eframe::set_value(storage, STORAGE_TARGET_SERVER, &self.state.target_server_ui);
+		eframe::set_value(storage, STORAGE_ACCOUNTS, &self.authenticator.accounts);
+	}
+
 	fn update(&mut self, ctx: &Context, _frame: &mut eframe::Frame) {
-		for msg in self.worker_handle.recv_messages() {
-		    self.handle_message(msg, ctx);
+		for (id, msg) in self.worker_handle.recv_messages() {
+		    self.handle_message(id, msg, ctx);
 		}
 
 		self.top_bar(ctx);
@@ -373,6 +718,8 @@ fn map(
 ) -> egui::Response {
 	ui.add(Map::new(tiles, map_memory, places::school())
 		.zoom_with_ctrl(editor_plugin.map_state.zoom_with_ctrl)
+		.panning(editor_plugin.editor_state.dragged.is_none())
+		.double_click_to_zoom(editor_plugin.map_state.tool != Tool::DrawWay) // finishes the drawn way instead
 		.with_plugin(editor_plugin)
 	)
 }
@@ -382,21 +729,53 @@ fn title_bar_button<'a>(text: &str, img: Image<'a>) -> Button<'a> {
 		.min_size(Vec2::new(0.0, TOP_BAR_BUTTON_SIZE))
 }
 
-fn server_selector(ui: &mut Ui, value: &mut TargetServer) {
+// Returns true if another account was selected.
+fn account_selector(ui: &mut Ui, accounts: &mut Accounts) -> bool {
+	use egui::ComboBox;
+
+	let prev = accounts.active;
+
+	ui.horizontal(|ui| {
+		ui.label("Account");
+		ComboBox::from_id_salt(ui.id().with("account"))
+			.selected_text(accounts.active().map_or("", |x| x.user.display_name.as_str()))
+			.show_ui(ui, |ui| {
+				for account in &accounts.list {
+					ui.selectable_value(&mut accounts.active, Some(account.user.id), &account.user.display_name);
+				}
+			});
+	});
+
+	accounts.active != prev
+}
+
+fn server_selector(ui: &mut Ui, value: &mut TargetServer, servers: &mut Vec<TargetServer>) {
 	use egui::{ComboBox, Grid};
 
+	let mut remove = None;
+
 	ui.horizontal(|ui| {
 		ui.label("Server");
 		ComboBox::from_id_salt(ui.id())
-			.selected_text(value.description())
+			.selected_text(&value.name)
 			.show_ui(ui, |ui| {
-				Grid::new(ui.id()).num_columns(TargetServer::ITER.len()).show(ui, |ui| {
-					for server in TargetServer::ITER {
-						ui.selectable_value(value, server, server.description());
-						ui.hyperlink(format!("https://{}", server.base_url()));
+				Grid::new(ui.id()).num_columns(3).show(ui, |ui| {
+					for (i, server) in servers.iter().enumerate() {
+						ui.selectable_value(value, server.clone(), &server.name);
+						ui.hyperlink(server.base_url());
+						if !server.is_builtin() && ui.small_button("🗑").on_hover_text("Remove server").clicked() {
+							remove = Some(i);
+						}
 						ui.end_row();
 					}
 				});
 			});
 	});
+
+	if let Some(i) = remove {
+		let server = servers.remove(i);
+		if *value == server {
+			*value = TargetServer::default();
+		}
+	}
 }
@@ -4,18 +4,19 @@ pub mod consts;
 pub mod attribute2d;
 pub mod states;
 pub mod r_star;
+pub mod merge;
 
-use super::osm::Bbox;
+use super::osm::{Bbox, Tile};
 use super::places::school;
 use crate::app::editor::r_star::WebMercatorPoint;
 use crate::app::windows::OverlapSelectorResult;
-use cache::{Change, EditorOsmData, ElementId, ElementRef, MAX_VIEW_OFFSET};
+use cache::{has_area_tags, Change, EditorOsmData, ElementId, ElementRef, MAX_VIEW_OFFSET};
 use consts::{osm::*, *};
-use eframe::egui::{Color32, FontId, Pos2, Response, Stroke, Ui};
+use eframe::egui::{Color32, CursorIcon, FontId, PointerButton, Pos2, Response, Shape, Stroke, Ui, Vec2};
 use eframe::epaint::{CircleShape, ColorMode, PathShape, PathStroke, RectShape, StrokeKind, TextShape};
 use osm_parser::*;
 use rstar::AABB;
-use states::{CacheFlag, MapState, SelectionFlag};
+use states::{CacheFlag, MapState, SelectionFlag, Tool};
 use std::sync::Arc;
 use visual::{FillMode, Visualization};
 use walkers::{MapMemory, Plugin, Position, Projector};
@@ -37,6 +38,71 @@ pub struct EditorPluginState {
 	pub last_click_coords: Position,
 	pub overlap_selector_elements: Vec<ElementId>,
 	pub overlap_selector_pos: Pos2,
+	pub dragged: Option<NodeDrag>, // map panning is disabled while a node is dragged
+	pub drawing: Option<Drawing>,
+}
+
+pub struct NodeDrag {
+	pub original: Node, // state before the drag, recorded as the change once it ends
+	pub grab_offset: Vec2, // from the pointer to the node, so it does not jump to the pointer
+}
+
+// Way that is drawn by clicking points, see Tool::DrawWay.
+pub enum Drawing {
+	Start(DrawTarget), // nothing is created until the second point
+	Way(Id),
+}
+
+// Where a drawn point is placed, nodes and ways close to the pointer are snapped to.
+#[derive(Clone, Copy, PartialEq)]
+pub enum DrawTarget {
+	Node(Id),
+	Segment(Id, [Id; 2], Position), // new node, connected to the way between the two nodes
+	Free(Position), // new node
+}
+
+impl DrawTarget {
+	// Returns the node at the target, new nodes are created and connected to the snapped way.
+	// A segment split in the meantime, for example by the first point of the same way, is connected at its closest part.
+	fn resolve(self, osm: &mut EditorOsmData) -> Id {
+		match self {
+			Self::Node(id) => id,
+			Self::Segment(way, segment, pos) => {
+				let id = Self::Free(pos).resolve(osm);
+				// the way may have lost the segment entirely, the node stays free then
+				osm.insert_node_into_way(way, segment, id);
+				id
+			}
+			Self::Free(pos) => osm.create_node(Coordinate::new(pos.y(), pos.x()), Tags::default()),
+		}
+	}
+
+	fn projected(&self, osm: &EditorOsmData, projector: &Projector) -> Option<Pos2> {
+		match self {
+			Self::Node(id) => osm.get_projected_pos(id),
+			Self::Segment(_, _, pos) | Self::Free(pos) => Some(projector.project(*pos).to_pos2()),
+		}
+	}
+}
+
+impl EditorPluginState {
+	// Drops elements that no longer exist, for example after their creation was undone.
+	pub fn forget_missing(&mut self, osm: &EditorOsmData) {
+		let exists = |id: &ElementId| match id {
+			ElementId::Node(id) => osm.data.nodes.contains_key(id),
+			ElementId::Way(id) => osm.data.ways.contains_key(id),
+		};
+
+		self.hovered.retain(exists);
+		self.overlap_selector_elements.retain(exists);
+		self.selected.take_if(|x| !exists(x));
+		self.dragged.take_if(|x| !osm.data.nodes.contains_key(&x.original.id));
+		self.drawing.take_if(|x| match x {
+			Drawing::Start(DrawTarget::Node(id)) => !osm.data.nodes.contains_key(id),
+			Drawing::Start(DrawTarget::Segment(id, ..)) | Drawing::Way(id) => !osm.data.ways.contains_key(id),
+			Drawing::Start(DrawTarget::Free(_)) => false,
+		});
+	}
 }
 
 impl Plugin for EditorPlugin<'_> {
@@ -51,7 +117,7 @@ impl Plugin for EditorPlugin<'_> {
 		}
 
 		let mouse = resp.hover_pos();
-		let clicked = resp.clicked();
+		let clicked = resp.clicked() && self.map_state.tool == Tool::Select; // other tools handle clicks themselves
 
 		let should_draw_nodes = curr_zoom > NODE_MIN_ZOOM;
 
@@ -67,6 +133,38 @@ impl Plugin for EditorPlugin<'_> {
 			target_fill = FillMode::Full;
 		}
 
+		/* drag nodes, using the hovered node of the previous frame */ {
+			let (pressed, down) = ui.input(|i| (i.pointer.primary_pressed(), i.pointer.primary_down()));
+
+			if pressed
+				&& self.map_state.tool == Tool::Select
+				&& self.editor_state.overlap_selector_elements.is_empty()
+				&& let Some(pointer) = resp.interact_pointer_pos()
+				&& let Some(ElementId::Node(id)) = self.editor_state.hovered.first()
+				&& let Some(pos) = self.osm.get_projected_pos(id)
+			{
+				self.editor_state.dragged = Some(NodeDrag {
+					original: self.osm.data.nodes.get(id).expect("id not found in data").clone(),
+					grab_offset: pos - pointer,
+				});
+			}
+
+			if !down && let Some(drag) = self.editor_state.dragged.take() {
+				let node = self.osm.data.nodes.get(&drag.original.id).expect("id not found in data").clone();
+				if (node.pos.lat, node.pos.lon) != (drag.original.pos.lat, drag.original.pos.lon) {
+					self.osm.record_change(Change::UpdateNode(drag.original, node));
+					self.map_state.record_used_provider();
+				}
+			} else if let Some(drag) = &self.editor_state.dragged
+				&& resp.dragged_by(PointerButton::Primary)
+				&& let Some(pointer) = resp.interact_pointer_pos()
+			{
+				let projected = pointer + drag.grab_offset;
+				let pos = projector.unproject(projected.to_vec2());
+				self.osm.drag_node(drag.original.id, Coordinate::new(pos.y(), pos.x()), projected);
+			}
+		}
+
 		self.editor_state.hovered.clear();
 
 		/* update editor state */ {
@@ -82,6 +180,112 @@ impl Plugin for EditorPlugin<'_> {
 			self.editor_state.map_bbox.top = tl.y();
 		}
 
+		/* place new points */ {
+			if self.map_state.tool == Tool::AddPoi {
+				// points are only placed where the existing nodes are visible
+				if resp.hovered() {
+					ui.ctx().set_cursor_icon(if should_draw_nodes { CursorIcon::Crosshair } else { CursorIcon::NotAllowed });
+				}
+
+				if should_draw_nodes && resp.clicked() && let Some(pointer) = resp.interact_pointer_pos() {
+					let pos = projector.unproject(pointer.to_vec2());
+					let (_, key, value) = POI_PRESETS[self.map_state.poi_preset];
+					let mut tags = Tags::default();
+					tags.insert(key.into(), value.into());
+
+					// selected so the tags can be completed right away
+					let id = self.osm.create_node(Coordinate::new(pos.y(), pos.x()), tags);
+					self.editor_state.selected = Some(ElementId::Node(id));
+					self.map_state.tool = Tool::Select;
+					self.map_state.record_used_provider();
+				}
+			}
+		}
+
+		/* draw new ways */ {
+			let mut finished = self.map_state.tool == Tool::DrawWay && resp.double_clicked();
+
+			if self.map_state.tool == Tool::DrawWay {
+				if resp.hovered() {
+					ui.ctx().set_cursor_icon(CursorIcon::Crosshair);
+				}
+
+				// the first click of a double-click already added the last point
+				if !finished && resp.clicked() && let Some(pointer) = resp.interact_pointer_pos() {
+					let (target, _) = self.snap(pointer, projector);
+					let drawn_way_exists = match &self.editor_state.drawing {
+						Some(Drawing::Way(id)) => self.osm.data.ways.contains_key(id),
+						_ => false,
+					};
+
+					self.editor_state.drawing = match self.editor_state.drawing.take() {
+						None => Some(Drawing::Start(target)),
+						// the drawn way may have been removed in the meantime, for example by undo
+						Some(Drawing::Way(_)) if !drawn_way_exists => Some(Drawing::Start(target)),
+						Some(Drawing::Start(first)) if first == target => Some(Drawing::Start(first)),
+						Some(Drawing::Start(first)) => {
+							let nodes = vec![first.resolve(self.osm), target.resolve(self.osm)];
+							let (_, key, value) = WAY_PRESETS[self.map_state.way_preset];
+							let mut tags = Tags::default();
+							tags.insert(key.into(), value.into());
+
+							Some(Drawing::Way(self.osm.create_way(nodes, tags)))
+						}
+						Some(Drawing::Way(id)) => {
+							let mut nodes = self.osm.data.ways.get(&id).expect("id not found in data").nodes.clone();
+							match target {
+								DrawTarget::Node(node) if nodes.last() == Some(&node) => {}, // clicked again
+								DrawTarget::Node(node) if nodes.first() == Some(&node) => {
+									// closing the ring finishes the way
+									if nodes.len() >= 3 {
+										nodes.push(node);
+										self.osm.set_way_nodes(id, nodes);
+										finished = true;
+									}
+								}
+								_ => {
+									nodes.push(target.resolve(self.osm));
+									self.osm.set_way_nodes(id, nodes);
+								}
+							}
+							Some(Drawing::Way(id))
+						}
+					};
+
+					self.map_state.record_used_provider();
+				}
+			}
+
+			// switching to another tool finishes the way as well
+			let switched = self.map_state.tool != Tool::DrawWay && self.editor_state.drawing.is_some();
+
+			// areas are only finished as a closed ring, it is closed automatically once it has enough nodes
+			if (finished || switched)
+				&& let Some(Drawing::Way(id)) = self.editor_state.drawing
+				&& let Some(way) = self.osm.data.ways.get(&id)
+				&& !is_way_closed(way)
+				&& has_area_tags(&way.tags)
+			{
+				if way.nodes.len() >= 3 {
+					let mut nodes = way.nodes.clone();
+					nodes.push(nodes[0]);
+					self.osm.set_way_nodes(id, nodes);
+				} else {
+					finished = false;
+				}
+			}
+
+			if finished || switched {
+				if let Some(Drawing::Way(id)) = self.editor_state.drawing.take() {
+					self.editor_state.selected = Some(ElementId::Way(id));
+				}
+				// the tool picked by the user is kept
+				if finished {
+					self.map_state.tool = Tool::Select;
+				}
+			}
+		}
+
 		/* update elements in view */ {
 			if !self.osm.data.nodes.is_empty() {
 				let p_start = projector.project(self.osm.view_start);
@@ -159,6 +363,15 @@ impl Plugin for EditorPlugin<'_> {
 		// todo: https://github.com/Swarkin/walkers-editor/issues/20
 		let mut shapes = Vec::with_capacity(capacity);
 
+		/* draw outline of the downloaded area, below the data */ {
+			let stroke = Stroke::new(LOADED_AREA_WIDTH * self.map_state.scale_factor, LOADED_AREA_COLOR);
+			for [a, b] in Tile::outline(&self.map_state.downloaded_tiles) {
+				let a = projector.project(Position::new(a.0, a.1)).to_pos2();
+				let b = projector.project(Position::new(b.0, b.1)).to_pos2();
+				shapes.push(Shape::line_segment([a, b], stroke));
+			}
+		}
+
 		/* draw osm data and detect interactions */ {
 			// 1. draw areas
 			// todo: is it faster to iterate over the key-value pairs directly?
@@ -433,6 +646,7 @@ impl Plugin for EditorPlugin<'_> {
 							if self.is_way_relevant(&way.tags) {
 								if let Some(change) = self.way_editing_ui(ui, way.id, projector.project(self.editor_state.last_click_coords).to_pos2()) {
 									self.osm.apply_change(change);
+									self.map_state.record_used_provider();
 								}
 							}
 							true
@@ -468,6 +682,26 @@ impl Plugin for EditorPlugin<'_> {
 			}
 		}
 
+		/* draw the next segment of the drawn way */ {
+			if self.map_state.tool == Tool::DrawWay && let Some(mouse) = mouse {
+				let (_, pos) = self.snap(mouse, projector);
+				let last = match &self.editor_state.drawing {
+					Some(Drawing::Start(target)) => target.projected(self.osm, projector),
+					Some(Drawing::Way(id)) => self.osm.data.ways.get(id)
+						.and_then(|x| x.nodes.last())
+						.and_then(|x| self.osm.get_projected_pos(x)),
+					None => None,
+				};
+
+				let stroke = Stroke::new(WAY_WIDTH * 2.0 * self.map_state.scale_factor, SELECTION_COLOR);
+				if let Some(last) = last {
+					shapes.push(Shape::line_segment([last, pos], stroke));
+					shapes.push(self.draw_point_drawn_at(last).into());
+				}
+				shapes.push(self.draw_point_drawn_at(pos).into());
+			}
+		}
+
 		shapes.extend(shapes_hover_tooltip);
 
 		// we want to preallocate as much memory as possible without overallocating
@@ -551,6 +785,15 @@ impl EditorPlugin<'_> {
 		}
 	}
 
+	const fn draw_point_drawn_at(&self, center: Pos2) -> CircleShape {
+		CircleShape {
+			center,
+			radius: NODE_SIZE * self.map_state.scale_factor,
+			fill: NODE_COLOR,
+			stroke: Stroke { width: NODE_STROKE_WIDTH + SELECTION_SIZE_INCREASE, color: SELECTION_COLOR },
+		}
+	}
+
 	fn draw_node_dynamic(&self, id: &Id) -> CircleShape {
 		if self.osm.node_usage.get(id).expect("id not found in cache").len() > 1 {
 			self.draw_node_connected(id)
@@ -686,6 +929,48 @@ impl EditorPlugin<'_> {
 		}
 	}
 
+	// Finds the node or way segment to place a drawn point on, returns it with its position on screen.
+	// The drawn way is skipped except for its first node, which closes the ring.
+	// Called before the caches are refreshed, so elements changed since the previous frame may be missing.
+	fn snap(&self, mouse: Pos2, projector: &Projector) -> (DrawTarget, Pos2) {
+		let range_sq = (SNAP_DISTANCE * self.map_state.scale_factor).powi(2);
+		let drawn = match &self.editor_state.drawing {
+			Some(Drawing::Way(id)) => self.osm.data.ways.get(id),
+			_ => None,
+		};
+		let ways = || self.osm.ways_in_view.iter()
+			.filter_map(|id| self.osm.data.ways.get(id))
+			.filter(|way| drawn.is_none_or(|x| x.id != way.id));
+
+		// nodes take precedence over the ways they are part of
+		let closest_node = ways()
+			.flat_map(|way| &way.nodes)
+			.chain(&self.osm.node_dedup.orphan_nodes)
+			.chain(drawn.and_then(|x| x.nodes.first()))
+			.filter_map(|id| Some((*id, self.osm.get_projected_pos(id)?)))
+			.min_by(|a, b| a.1.distance_sq(mouse).total_cmp(&b.1.distance_sq(mouse)));
+
+		if let Some((id, pos)) = closest_node && pos.distance_sq(mouse) < range_sq {
+			return (DrawTarget::Node(id), pos);
+		}
+
+		for way in ways() {
+			let Some(points) = way.nodes.iter().map(|x| self.osm.get_projected_pos(x)).collect::<Option<Vec<_>>>() else { continue; };
+			if distance_to_way(&points, mouse) >= range_sq { continue; }
+
+			let closest_segment = points.windows(2)
+				.map(|x| closest_point_on_segment(mouse, &[x[0], x[1]]))
+				.enumerate()
+				.min_by(|a, b| a.1.distance_sq(mouse).total_cmp(&b.1.distance_sq(mouse)));
+
+			if let Some((i, pos)) = closest_segment {
+				return (DrawTarget::Segment(way.id, [way.nodes[i], way.nodes[i + 1]], projector.unproject(pos.to_vec2())), pos);
+			}
+		}
+
+		(DrawTarget::Free(projector.unproject(mouse.to_vec2())), mouse)
+	}
+
 	const fn should_detect_interactions(&self, mouse: Option<Pos2>, selection_flag: SelectionFlag) -> bool {
 		mouse.is_some()
 			&& self.map_state.selection_mode & selection_flag as u8 != 0
@@ -694,7 +979,7 @@ impl EditorPlugin<'_> {
 }
 
 #[allow(clippy::many_single_char_names)]
-fn distance_to_segment_sq(p: Pos2, points: &[Pos2; 2]) -> f32 {
+fn closest_point_on_segment(p: Pos2, points: &[Pos2; 2]) -> Pos2 {
 	let x = points[0];
 	let y = points[1];
 
@@ -707,22 +992,20 @@ fn distance_to_segment_sq(p: Pos2, points: &[Pos2; 2]) -> f32 {
 	let len_sq = c.mul_add(c, d * d);
 	let param = if len_sq == 0f32 { -1f32 } else { dot / len_sq };
 
-	let xx;
-	let yy;
-
 	if param < 0f32 {
-		xx = x.x;
-		yy = x.y;
+		x
 	} else if param > 1f32 {
-		xx = y.x;
-		yy = y.y;
+		y
 	} else {
-		xx = param.mul_add(c, x.x);
-		yy = param.mul_add(d, x.y);
+		Pos2::new(param.mul_add(c, x.x), param.mul_add(d, x.y))
 	}
+}
+
+fn distance_to_segment_sq(p: Pos2, points: &[Pos2; 2]) -> f32 {
+	let closest = closest_point_on_segment(p, points);
 
-	let dx = p.x - xx;
-	let dy = p.y - yy;
+	let dx = p.x - closest.x;
+	let dy = p.y - closest.y;
 	dx.mul_add(dx, dy * dy)
 }
 
@@ -1,7 +1,9 @@
+use super::merge::{rebase_node, rebase_way, Conflict};
 use super::r_star::*;
 use super::states::{CacheBitflag, CacheFlag};
-use crate::app::editor::is_way_closed;
+use crate::app::editor::{distance_to_segment_sq, is_way_closed};
 use crate::app::icons::*;
+use crate::app::osmchange::{from_osmchange_id, is_placeholder_id, DiffEntry, DiffResult};
 use eframe::egui::{Color32, ImageSource, Mesh, Pos2, TextureId, Vec2};
 use eframe::epaint::{Vertex, WHITE_UV};
 use indexmap::IndexMap;
@@ -66,21 +68,94 @@ pub struct MeshData {
 	pub vertices: Vec<Vertex>,
 }
 
-#[derive(Debug)]
+// Created elements use placeholder IDs, see osmchange::to_osmchange_id.
+// Deleted elements are stored as they were before the deletion, so every change can be inverted.
+#[derive(Debug, Clone)]
 pub enum Change {
-	UpdateWay(Id, Way),
+	CreateNode(Node),
+	CreateWay(Way),
+	UpdateNode(Node, Node), // state before and after the update
+	UpdateWay(Way, Way), // state before and after the update
+	DeleteNode(Node),
+	DeleteWay(Way),
+}
+
+impl Change {
+	pub const fn element_id(&self) -> ElementId {
+		match self {
+			Self::CreateNode(node) | Self::DeleteNode(node) | Self::UpdateNode(_, node) => ElementId::Node(node.id),
+			Self::CreateWay(way) | Self::DeleteWay(way) | Self::UpdateWay(_, way) => ElementId::Way(way.id),
+		}
+	}
+
+	// Replaces the tags of the element.
+	pub fn update_tags(element: &ElementRef, tags: Tags) -> Self {
+		match element {
+			ElementRef::Node(node) => Self::UpdateNode((*node).clone(), Node { tags, ..(*node).clone() }),
+			ElementRef::Way(way) => Self::UpdateWay((*way).clone(), Way { tags, ..(*way).clone() }),
+		}
+	}
+
+	// The change that restores the state before this one.
+	pub fn inverse(&self) -> Self {
+		match self {
+			Self::CreateNode(node) => Self::DeleteNode(node.clone()),
+			Self::CreateWay(way) => Self::DeleteWay(way.clone()),
+			Self::UpdateNode(prev, node) => Self::UpdateNode(node.clone(), prev.clone()),
+			Self::UpdateWay(prev, way) => Self::UpdateWay(way.clone(), prev.clone()),
+			Self::DeleteNode(node) => Self::CreateNode(node.clone()),
+			Self::DeleteWay(way) => Self::CreateWay(way.clone()),
+		}
+	}
+
+	// Moves the change onto a newer version of the element, what it changed compared to the base is kept.
+	fn rebase(&mut self, base: &Element, server: &Element) {
+		match (self, base, server) {
+			(Self::UpdateNode(prev, node), Element::Node(base), Element::Node(server)) => {
+				*prev = rebase_node(base, prev, server);
+				*node = rebase_node(base, node, server);
+			}
+			(Self::UpdateWay(prev, way), Element::Way(base), Element::Way(server)) => {
+				*prev = rebase_way(base, prev, server);
+				*way = rebase_way(base, way, server);
+			}
+			(Self::DeleteNode(node), Element::Node(base), Element::Node(server)) => *node = rebase_node(base, node, server),
+			(Self::DeleteWay(way), Element::Way(base), Element::Way(server)) => *way = rebase_way(base, way, server),
+			_ => {}, // created elements are not on the server yet
+		}
+	}
+
+	// The change leading from another state of the element to the same result, None if the element is already in it.
+	fn starting_from(&self, element: Element) -> Option<Self> {
+		match (element, self) {
+			(Element::Node(node), Self::UpdateNode(_, next)) => (node.tags != next.tags || (node.pos.lat, node.pos.lon) != (next.pos.lat, next.pos.lon))
+				.then(|| Self::UpdateNode(node, next.clone())),
+			(Element::Way(way), Self::UpdateWay(_, next)) => (way.tags != next.tags || way.nodes != next.nodes)
+				.then(|| Self::UpdateWay(way, next.clone())),
+			(Element::Node(node), Self::DeleteNode(_)) => Some(Self::DeleteNode(node)),
+			(Element::Way(way), Self::DeleteWay(_)) => Some(Self::DeleteWay(way)),
+			_ => None,
+		}
+	}
 }
 
 impl Display for Change {
 	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-		match self {
-			Self::UpdateWay(id, way) => {
-				if let Some(name) = way.tags.get("name") {
-					write!(f, "Updated {name}")
-				} else {
-					write!(f, "Updated Way {id}")
-				}
-			},
+		let (action, kind, id, tags) = match self {
+			Self::CreateNode(node) => ("Created", "Node", node.id, &node.tags),
+			Self::CreateWay(way) => ("Created", "Way", way.id, &way.tags),
+			Self::UpdateNode(_, node) => ("Updated", "Node", node.id, &node.tags),
+			Self::UpdateWay(_, way) => ("Updated", "Way", way.id, &way.tags),
+			Self::DeleteNode(node) => ("Deleted", "Node", node.id, &node.tags),
+			Self::DeleteWay(way) => ("Deleted", "Way", way.id, &way.tags),
+		};
+
+		if let Some(name) = tags.get("name") {
+			write!(f, "{action} {name}")
+		} else if is_placeholder_id(id) {
+			write!(f, "{action} new {kind}")
+		} else {
+			write!(f, "{action} {kind} {id}")
 		}
 	}
 }
@@ -98,7 +173,11 @@ pub struct EditorOsmData {
 	pub view_timing: u32,
 	pub refresh_in_view_flag: bool,
 
-	pub changes: Vec<Change>,
+	pub changes: Vec<Change>, // applied changes, the last one is undone first
+	pub undone: Vec<Change>, // undone changes, the last one is redone first
+	pub base: HashMap<ElementId, Element>, // state of changed elements before the first change
+	pub conflicts: Vec<Conflict>, // edited elements that were also changed on the server, found while downloading
+	placeholder_count: Id,
 	pub cache_flags: CacheBitflag,
 	#[cfg(feature = "debug")]
 	pub cache_debug: CacheDebug,
@@ -120,6 +199,28 @@ pub struct EditorOsmData {
 	pub mesh_offset_resize: Vec2,
 }
 
+#[derive(Debug, Clone)]
+pub enum Element {
+	Node(Node),
+	Way(Way),
+}
+
+impl Element {
+	pub const fn element_ref(&self) -> ElementRef {
+		match self {
+			Self::Node(n) => ElementRef::Node(n),
+			Self::Way(w) => ElementRef::Way(w),
+		}
+	}
+
+	pub const fn version(&self) -> u32 {
+		match self {
+			Self::Node(n) => n.version,
+			Self::Way(w) => w.version,
+		}
+	}
+}
+
 #[derive(Debug, Clone)]
 pub enum ElementRef<'a> {
 	Node(&'a Node),
@@ -170,7 +271,7 @@ impl ElementRef<'_> {
 	}
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
 pub enum ElementId {
 	Node(Id),
 	Way(Id),
@@ -186,21 +287,364 @@ impl ElementId {
 
 #[allow(clippy::trivially_copy_pass_by_ref, clippy::cast_possible_truncation)]
 impl EditorOsmData {
+	// Applies a new change, the undone changes can no longer be redone afterwards.
 	pub fn apply_change(&mut self, change: Change) {
+		self.undone.clear();
+		self.push_change(change);
+	}
+
+	// Reverts the last change, returns whether there was one.
+	pub fn undo(&mut self) -> bool {
+		let Some(change) = self.changes.pop() else { return false; };
+		self.apply_to_data(&change.inverse());
+
+		// without changes left, the element counts as unedited again
+		let id = change.element_id();
+		if !self.changes.iter().any(|x| x.element_id() == id) {
+			self.base.remove(&id);
+			if let Some(i) = self.conflicts.iter().position(|x| x.element == id) {
+				let conflict = self.conflicts.remove(i);
+				self.insert_element(conflict.server);
+				self.refresh_after_structural_change();
+			}
+		}
+
+		self.undone.push(change);
+		true
+	}
+
+	// Applies the last undone change again, returns whether there was one.
+	pub fn redo(&mut self) -> bool {
+		let Some(change) = self.undone.pop() else { return false; };
+		self.push_change(change);
+		true
+	}
+
+	// Records a change that was already written into the data, like a node moved by dragging it.
+	pub fn record_change(&mut self, change: Change) {
+		self.undone.clear();
+		self.record_base_of(&change);
+		self.changes.push(change);
+	}
+
+	fn push_change(&mut self, change: Change) {
+		self.record_base_of(&change);
+		self.apply_to_data(&change);
+		self.changes.push(change);
+	}
+
+	// Writes the change into the data and invalidates the caches it affects.
+	fn apply_to_data(&mut self, change: &Change) {
+		match change {
+			Change::CreateNode(node) => {
+				self.data.nodes.insert(node.id, node.clone());
+				self.refresh_after_structural_change();
+			}
+			Change::CreateWay(way) => {
+				self.data.ways.insert(way.id, way.clone());
+				self.refresh_after_structural_change();
+			}
+			Change::DeleteNode(node) => {
+				self.data.nodes.remove(&node.id);
+				self.refresh_after_structural_change();
+			}
+			Change::DeleteWay(way) => {
+				self.data.ways.remove(&way.id);
+				self.refresh_after_structural_change();
+			}
+			Change::UpdateNode(prev, node) => {
+				self.data.nodes.insert(node.id, node.clone());
+
+				// the tags of nodes are not cached, a moved node keeps its ways and only needs to be projected again
+				if (prev.pos.lat, prev.pos.lon) != (node.pos.lat, node.pos.lon) {
+					self.rtree_data.move_node(&self.data, node.id, &prev.pos);
+					self.cache_flags |= CacheFlag::NodeDedup as u8 | CacheFlag::NodeProjection as u8 | CacheFlag::WayMeshAndAreaSize as u8 | CacheFlag::AreaSizeOrdered as u8;
+				}
+			}
+			Change::UpdateWay(prev, way) => {
+				self.data.ways.insert(way.id, way.clone());
+
+				if prev.nodes == way.nodes {
+					// tags decide whether the way is drawn as an area, the elements in view stay the same
+					self.cache_flags |= CacheFlag::WayArea as u8 | CacheFlag::NodeDedup as u8 | CacheFlag::WayMeshAndAreaSize as u8 | CacheFlag::AreaSizeOrdered as u8;
+				} else {
+					self.refresh_after_structural_change();
+				}
+			}
+		}
+	}
+
+	fn record_base_of(&mut self, change: &Change) {
 		match change {
-			Change::UpdateWay(id, way) => {
-				self.data.ways.insert(id, way.clone());
+			Change::DeleteNode(node) | Change::UpdateNode(node, _) => self.record_base(Element::Node(node.clone())),
+			Change::DeleteWay(way) | Change::UpdateWay(way, _) => self.record_base(Element::Way(way.clone())),
+			Change::CreateNode(_) | Change::CreateWay(_) => {},
+		}
+	}
+
+	// Keeps the state of an element before its first change, used as the common ancestor when merging.
+	fn record_base(&mut self, element: Element) {
+		let id = element.element_ref().element_id();
+		if !is_placeholder_id(*id.id_ref()) {
+			self.base.entry(id).or_insert(element);
+		}
+	}
+
+	// Replaces the local changes of a conflicting element with the merge result.
+	pub fn resolve_conflict(&mut self, conflict: &Conflict) {
+		self.apply_resolution(conflict);
+
+		// the geometry may have changed
+		self.refresh_after_structural_change();
+	}
+
+	fn apply_resolution(&mut self, conflict: &Conflict) {
+		let id = conflict.element.clone();
+		let (server, change) = conflict.resolve();
+		let base = self.base.remove(&id);
+		self.conflicts.retain(|x| x.element != id);
+
+		match (base, change) {
+			(Some(base), Some(change)) => {
+				// the local changes are moved onto the server version in place, so they can still be undone step by step
+				for x in self.changes.iter_mut().chain(&mut self.undone).filter(|x| x.element_id() == id) {
+					x.rebase(&base, &server);
+				}
+				self.base.insert(id.clone(), server);
+
+				if let Some(last) = self.changes.iter().rfind(|x| x.element_id() == id).cloned() {
+					self.apply_to_data(&last);
+				}
+
+				// decisions of the user that differ from the rebased changes
+				if let Some(step) = self.element(&id).and_then(|x| change.starting_from(x)) {
+					self.push_change(step);
+				}
+			}
+			// the server version is kept, the local changes are dropped
+			(_, change) => {
+				self.changes.retain(|x| x.element_id() != id);
+				self.undone.retain(|x| x.element_id() != id);
+				self.insert_element(server);
+
+				if let Some(change) = change {
+					self.push_change(change);
+				}
+			}
+		}
+	}
 
-				if let Some(Change::UpdateWay(prev_id, prev_way)) = self.changes.last_mut() {
-					if *prev_id == id {
-						*prev_way = way;
-						return; // do not record a new change
+	// Merges downloaded data, newer versions replace unedited elements and are merged with edited ones.
+	// Edits are rebased onto the newer version if they do not overlap, otherwise the element is added to the conflicts.
+	pub fn merge_downloaded(&mut self, from: OsmData) {
+		if from.is_empty() { return; }
+		self.invalidate_caches_for(&from);
+
+		let elements = from.nodes.into_values().map(Element::Node)
+			.chain(from.ways.into_values().map(Element::Way));
+
+		for server in elements {
+			let Some(conflict) = self.update_element(server) else { continue; };
+
+			if conflict.is_clean() {
+				self.apply_resolution(&conflict);
+			} else {
+				self.conflicts.retain(|x| x.element != conflict.element);
+				self.conflicts.push(conflict);
+			}
+		}
+
+		self.rtree_data = RStarOsmData::from(&self.data);
+	}
+
+	// Returns the merge of an edited element with its newer version, other elements are updated directly.
+	fn update_element(&mut self, server: Element) -> Option<Conflict> {
+		let id = server.element_ref().element_id();
+		let local = self.element(&id);
+
+		let Some(base) = self.base.get(&id) else {
+			if local.is_none_or(|x| server.version() > x.version()) {
+				self.undone.retain(|x| x.element_id() != id); // would revert the newer version
+				self.insert_element(server);
+			}
+			return None;
+		};
+
+		// the version the edits are based on, or the one of the previous download
+		let known_version = self.conflicts.iter()
+			.find(|x| x.element == id)
+			.map_or(base.version(), |x| x.server.version());
+
+		(server.version() > known_version).then(|| Conflict::new(base, local, server))
+	}
+
+	pub fn element(&self, id: &ElementId) -> Option<Element> {
+		match id {
+			ElementId::Node(id) => self.data.nodes.get(id).cloned().map(Element::Node),
+			ElementId::Way(id) => self.data.ways.get(id).cloned().map(Element::Way),
+		}
+	}
+
+	fn insert_element(&mut self, element: Element) {
+		match element {
+			Element::Node(node) => { self.data.nodes.insert(node.id, node); },
+			Element::Way(way) => { self.data.ways.insert(way.id, way); },
+		}
+	}
+
+	// Returns a new ID for an element that does not exist on the server yet.
+	pub const fn new_placeholder_id(&mut self) -> Id {
+		let id = Id::MAX - self.placeholder_count;
+		self.placeholder_count += 1;
+		id
+	}
+
+	// Adds a node that does not exist on the server yet, returns its placeholder ID.
+	pub fn create_node(&mut self, pos: Coordinate, tags: Tags) -> Id {
+		let id = self.new_placeholder_id();
+		self.apply_change(Change::CreateNode(Node { id, pos, tags, version: 0, changeset: 0 }));
+		id
+	}
+
+	// Adds a way that does not exist on the server yet, returns its placeholder ID.
+	pub fn create_way(&mut self, nodes: Vec<Id>, tags: Tags) -> Id {
+		let id = self.new_placeholder_id();
+		self.apply_change(Change::CreateWay(Way { id, nodes, tags, version: 0, changeset: 0 }));
+		id
+	}
+
+	pub fn set_way_nodes(&mut self, id: Id, nodes: Vec<Id>) {
+		let Some(prev) = self.data.ways.get(&id) else { return; };
+		let way = Way { nodes, ..prev.clone() };
+		self.apply_change(Change::UpdateWay(prev.clone(), way));
+	}
+
+	// Connects a node to the way between two of its nodes, returns false if they are no longer part of the way in that order.
+	// The segment may have been split by nodes inserted in the meantime, the part closest to the node is used then.
+	pub fn insert_node_into_way(&mut self, id: Id, segment: [Id; 2], node: Id) -> bool {
+		let Some(way) = self.data.ways.get(&id) else { return false; };
+		let Some(pos) = self.data.nodes.get(&node).map(|x| &x.pos) else { return false; };
+		let Some(start) = way.nodes.iter().position(|x| *x == segment[0]) else { return false; };
+		let Some(len) = way.nodes[start + 1..].iter().position(|x| *x == segment[1]) else { return false; };
+
+		// relative to the node, so the small differences survive the conversion to f32
+		let local = |id: &Id| {
+			self.data.nodes.get(id).map_or(Pos2::new(f32::INFINITY, f32::INFINITY), |x| {
+				Pos2::new((x.pos.lon - pos.lon) as f32, (x.pos.lat - pos.lat) as f32)
+			})
+		};
+		let Some(i) = way.nodes[start..=start + len + 1]
+			.windows(2)
+			.map(|x| distance_to_segment_sq(Pos2::ZERO, &[local(&x[0]), local(&x[1])]))
+			.enumerate()
+			.min_by(|a, b| a.1.total_cmp(&b.1))
+			.map(|(i, _)| start + i)
+		else { return false; };
+
+		let mut nodes = way.nodes.clone();
+		nodes.insert(i + 1, node);
+		self.set_way_nodes(id, nodes);
+		true
+	}
+
+	// Elements were added or removed, the spatial index and all caches need to be rebuilt.
+	fn refresh_after_structural_change(&mut self) {
+		self.rtree_data = RStarOsmData::from(&self.data);
+		self.refresh_in_view_flag = true;
+	}
+
+	// Writes the new IDs and versions assigned by the server back into the data and drops the uploaded changes.
+	pub fn apply_diff_result(&mut self, diff: &DiffResult) {
+		let mut uploaded = HashSet::default();
+		let mut remapped_nodes = HashMap::default();
+		let mut ids_changed = false;
+
+		// with if-unused, the server keeps deleted elements that are still in use and reports them with a new version
+		let mut deleted_nodes = HashMap::default();
+		let mut deleted_ways = HashMap::default();
+		for change in &self.changes {
+			match change {
+				Change::DeleteNode(node) => { deleted_nodes.insert(node.id, node); },
+				Change::DeleteWay(way) => { deleted_ways.insert(way.id, way); },
+				_ => {},
+			}
+		}
+
+		for entry in &diff.entries {
+			match entry {
+				DiffEntry::Node(e) => {
+					let old_id = from_osmchange_id(e.old_id);
+					uploaded.insert(ElementId::Node(old_id));
+
+					let (mut node, restored) = match self.data.nodes.remove(&old_id) {
+						Some(node) => (node, false),
+						None => match (e.new_id, deleted_nodes.get(&old_id)) {
+							(Some(_), Some(node)) => ((*node).clone(), true),
+							_ => continue,
+						},
+					};
+					ids_changed |= restored;
+					let (Some(new_id), Some(new_version)) = (e.new_id, e.new_version) else {
+						ids_changed = true; // deleted
+						continue;
+					};
+
+					let new_id = from_osmchange_id(new_id);
+					if new_id != old_id {
+						remapped_nodes.insert(old_id, new_id);
+						ids_changed = true;
 					}
+
+					node.id = new_id;
+					node.version = new_version;
+					self.data.nodes.insert(new_id, node);
+				}
+				DiffEntry::Way(e) => {
+					let old_id = from_osmchange_id(e.old_id);
+					uploaded.insert(ElementId::Way(old_id));
+
+					let (mut way, restored) = match self.data.ways.remove(&old_id) {
+						Some(way) => (way, false),
+						None => match (e.new_id, deleted_ways.get(&old_id)) {
+							(Some(_), Some(way)) => ((*way).clone(), true),
+							_ => continue,
+						},
+					};
+					ids_changed |= restored;
+					let (Some(new_id), Some(new_version)) = (e.new_id, e.new_version) else {
+						ids_changed = true;
+						continue;
+					};
+
+					let new_id = from_osmchange_id(new_id);
+					ids_changed |= new_id != old_id;
+
+					way.id = new_id;
+					way.version = new_version;
+					self.data.ways.insert(new_id, way);
 				}
+				DiffEntry::Relation(_) => {}, // relations are not supported yet
+			}
+		}
 
-				self.changes.push(Change::UpdateWay(id, way));
+		self.changes.retain(|change| !uploaded.contains(&change.element_id()));
+		self.undone.clear(); // may refer to the uploaded elements by their placeholder IDs
+		self.base.retain(|id, _| !uploaded.contains(id));
+
+		if !remapped_nodes.is_empty() {
+			for way in self.data.ways.values_mut() {
+				for id in &mut way.nodes {
+					if let Some(new_id) = remapped_nodes.get(id) {
+						*id = *new_id;
+					}
+				}
 			}
 		}
+
+		if ids_changed {
+			self.rtree_data = RStarOsmData::from(&self.data);
+			self.refresh_in_view_flag = true;
+		}
 	}
 
 	pub fn get(&self, id: &Id) -> Option<ElementRef> {
@@ -243,6 +687,33 @@ impl EditorOsmData {
 		}
 	}
 
+	// Moves a node while it is dragged, without refreshing the caches of all elements in view.
+	// The projected position is given by the pointer, so the node does not need to be projected again.
+	pub fn drag_node(&mut self, id: Id, pos: Coordinate, projected: Pos2) {
+		let Some(node) = self.data.nodes.get_mut(&id) else { return; };
+		let from = std::mem::replace(&mut node.pos, pos);
+		self.rtree_data.move_node(&self.data, id, &from);
+
+		if let Some(origin) = self.projected_nodes.get_mut(&id) {
+			*origin = projected - self.node_offset_move - self.node_offset_resize;
+		}
+
+		// only the areas using the node change their shape
+		for way_id in self.node_usage.get(&id).into_iter().flatten() {
+			if !self.way_area.areas.contains(way_id) { continue; }
+
+			if let Some(mesh) = self.tessellate_area(way_id) {
+				self.way_mesh.insert(*way_id, mesh);
+			}
+
+			// keeps the direction of the partial fill, the order is only updated by the next refresh
+			let size = area_size(&self.get_projected_origin_positions_in_way(way_id));
+			if let Some(x) = self.area_size_ordered.get_mut(way_id) {
+				*x = size;
+			}
+		}
+	}
+
 	// Required caches:
 	// - NodeDedup
 	pub fn refresh_projected_nodes_cache(&mut self, projector: &Projector, start_pos: Position) {
@@ -399,43 +870,8 @@ impl EditorOsmData {
 		self.cache_flags &= !(CacheFlag::WayMeshAndAreaSize as u8);
 
 		for id in &self.way_area.areas {
-			// next 15 lines take ~10% of the total time
-			// todo: separate cache to eliminate doing this twice
-			let mut points = self.get_projected_positions_in_way(id).into_iter();
-
-			if let Some(first) = points.next() { // skip empty ways
-				let mut builder = Path::builder();
-				builder.begin(Point::new(first.x, first.y));
-
-				for p in points {
-					builder.line_to(Point::new(p.x, p.y));
-				}
-
-				builder.close();
-				let path = builder.build();
-
-				// next 15 lines take ~70% of the total time
-				// todo: re-use vertexbuffers allocation
-				let mut geometry: VertexBuffers<Vertex, u32> = VertexBuffers::new();
-				let mut tessellator = FillTessellator::new();
-
-				// todo: intersection handling
-				tessellator.tessellate_path(
-					&path,
-					&FillOptions::default().with_intersections(false),
-					&mut BuffersBuilder::new(&mut geometry, |vertex: FillVertex| {
-						Vertex {
-							pos: Pos2::from(vertex.position().to_array()),
-							uv: WHITE_UV,
-							color: Color32::WHITE,
-						}
-					}),
-				).expect("path tesselation failed");
-
-				self.way_mesh.insert(*id, MeshData {
-					indices: geometry.indices,
-					vertices: geometry.vertices,
-				});
+			if let Some(mesh) = self.tessellate_area(id) {
+				self.way_mesh.insert(*id, mesh);
 			}
 		}
 
@@ -443,28 +879,55 @@ impl EditorOsmData {
 		self.cache_debug.update(CacheFlag::WayMeshAndAreaSize, t.elapsed().as_micros() as u32);
 	}
 
+	// Returns None for empty ways.
+	fn tessellate_area(&self, id: &Id) -> Option<MeshData> {
+		// the mesh offsets are added again when drawing
+		let offset = self.mesh_offset_move + self.mesh_offset_resize;
+
+		// next 15 lines take ~10% of the total time
+		// todo: separate cache to eliminate doing this twice
+		let mut points = self.get_projected_positions_in_way(id).into_iter().map(|x| x - offset);
+
+		let first = points.next()?;
+		let mut builder = Path::builder();
+		builder.begin(Point::new(first.x, first.y));
+
+		for p in points {
+			builder.line_to(Point::new(p.x, p.y));
+		}
+
+		builder.close();
+		let path = builder.build();
+
+		// next 15 lines take ~70% of the total time
+		// todo: re-use vertexbuffers allocation
+		let mut geometry: VertexBuffers<Vertex, u32> = VertexBuffers::new();
+		let mut tessellator = FillTessellator::new();
+
+		// todo: intersection handling
+		tessellator.tessellate_path(
+			&path,
+			&FillOptions::default().with_intersections(false),
+			&mut BuffersBuilder::new(&mut geometry, |vertex: FillVertex| {
+				Vertex {
+					pos: Pos2::from(vertex.position().to_array()),
+					uv: WHITE_UV,
+					color: Color32::WHITE,
+				}
+			}),
+		).expect("path tesselation failed");
+
+		Some(MeshData {
+			indices: geometry.indices,
+			vertices: geometry.vertices,
+		})
+	}
+
 	// Builds off of the WayArea cache.
 	// Required caches:
 	// - NodeProjection
 	// - WayArea
 	pub fn refresh_area_size_ordered_cache(&mut self) {
-		// Shoelace formula for area calculation, returns twice the area.
-		fn area_size(points: &[Pos2]) -> f32 {
-			let n = points.len();
-			if n < 3 {
-				0.0
-			} else {
-				let mut area = 0.0;
-				for i in 0..n {
-					let p1 = points[i];
-					let p2 = points[(i + 1) % n];
-					area += p1.x.mul_add(p2.y, -(p2.x * p1.y));
-				}
-
-				area
-			}
-		}
-
 		debug_assert_eq!(self.cache_flags & (CacheFlag::NodeProjection as u8 | CacheFlag::WayArea as u8), 0);
 
 		#[cfg(feature = "debug")]
@@ -492,36 +955,30 @@ impl EditorOsmData {
 		self.cache_debug.update(CacheFlag::AreaSizeOrdered, t.elapsed().as_micros() as u32);
 	}
 
+	// Only adds elements that are missing, existing ones are kept as they are. See merge_downloaded for updating them.
 	pub fn append_new_nodes_ways(&mut self, from: OsmData) {
 		if from.is_empty() { return; }
+		self.invalidate_caches_for(&from);
 
-		if !from.ways.is_empty() {
-			self.cache_flags |= CacheFlag::WayArea as u8 | CacheFlag::WayMeshAndAreaSize as u8 | CacheFlag::AreaSizeOrdered as u8;
+		for (id, way) in from.ways {
+			self.data.ways.entry(id).or_insert(way);
 		}
 
-		if !from.nodes.is_empty() {
-			self.cache_flags |= CacheFlag::NodeProjection as u8 | CacheFlag::NodeOrphan as u8 | CacheFlag::NodeDedup as u8 | CacheFlag::NodeUsage as u8;
+		for (id, node) in from.nodes {
+			self.data.nodes.entry(id).or_insert(node);
 		}
 
-		for (id, way) in from.ways {
-			// todo: handle new versions
-			if self.data.ways.contains_key(&id) {
-				continue;
-			}
+		self.rtree_data = RStarOsmData::from(&self.data);
+	}
 
-			self.data.ways.insert(id, way);
+	fn invalidate_caches_for(&mut self, from: &OsmData) {
+		if !from.ways.is_empty() {
+			self.cache_flags |= CacheFlag::WayArea as u8 | CacheFlag::WayMeshAndAreaSize as u8 | CacheFlag::AreaSizeOrdered as u8;
 		}
 
-		for (id, node) in from.nodes {
-			// todo: handle new versions
-			if self.data.nodes.contains_key(&id) {
-				continue;
-			}
-
-			self.data.nodes.insert(id, node);
+		if !from.nodes.is_empty() {
+			self.cache_flags |= CacheFlag::NodeProjection as u8 | CacheFlag::NodeOrphan as u8 | CacheFlag::NodeDedup as u8 | CacheFlag::NodeUsage as u8;
 		}
-
-		self.rtree_data = RStarOsmData::from(&self.data);
 	}
 
 	pub fn refresh_elements_in_view(&mut self, aabb: &AABB<WebMercatorPoint>) {
@@ -558,11 +1015,31 @@ pub fn coordinate_to_pos(c: &Coordinate) -> Position {
 	Position::new(c.lon, c.lat)
 }
 
+// Shoelace formula for area calculation, returns twice the area.
+fn area_size(points: &[Pos2]) -> f32 {
+	let n = points.len();
+	if n < 3 {
+		0.0
+	} else {
+		let mut area = 0.0;
+		for i in 0..n {
+			let p1 = points[i];
+			let p2 = points[(i + 1) % n];
+			area += p1.x.mul_add(p2.y, -(p2.x * p1.y));
+		}
+
+		area
+	}
+}
+
 // Primitive area detection
 fn is_way_area(way: &Way) -> bool {
-	if !is_way_closed(way) || way.nodes.len() < 3 || way.tags.is_empty() { return false; }
+	is_way_closed(way) && way.nodes.len() >= 3 && has_area_tags(&way.tags)
+}
 
-	if let Some(area) = way.tags.get("area") {
+// Whether a closed way with these tags is an area
+pub fn has_area_tags(tags: &Tags) -> bool {
+	if let Some(area) = tags.get("area") {
 		match area.as_str() {
 			"yes" => return true,
 			"no" => return false,
@@ -571,8 +1048,288 @@ fn is_way_area(way: &Way) -> bool {
 	}
 
 	for key in ["building", "landuse", "natural", "leisure", "amenity", "playground"] {
-		if way.tags.contains_key(key) { return true; }
+		if tags.contains_key(key) { return true; }
 	}
 
 	false
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::app::editor::merge::Side;
+	use crate::app::editor::DrawTarget;
+	use crate::app::osmchange::OsmChange;
+
+	fn tags(pairs: &[(&str, &str)]) -> Tags {
+		let mut tags = Tags::default();
+		for (k, v) in pairs {
+			tags.insert((*k).into(), (*v).into());
+		}
+		tags
+	}
+
+	fn node(id: Id, version: u32) -> Node {
+		Node { id, pos: Coordinate::new(50.0, 10.0), tags: Tags::default(), version, changeset: 1 }
+	}
+
+	fn way(version: u32, tags: Tags) -> Way {
+		Way { id: 10, nodes: vec![1, 2], tags, version, changeset: 1 }
+	}
+
+	fn data(version: u32, way_tags: Tags) -> OsmData {
+		let mut data = OsmData::default();
+		data.nodes.insert(1, node(1, version));
+		data.nodes.insert(2, node(2, version));
+		data.ways.insert(10, way(version, way_tags));
+		data
+	}
+
+	fn edited(local_tags: Tags) -> EditorOsmData {
+		let mut osm = EditorOsmData::default();
+		osm.merge_downloaded(data(1, tags(&[("highway", "path"), ("surface", "gravel")])));
+		osm.apply_change(Change::UpdateWay(osm.data.ways[&10].clone(), way(1, local_tags)));
+		osm
+	}
+
+	#[test]
+	fn refresh_unedited() {
+		let mut osm = EditorOsmData::default();
+		osm.merge_downloaded(data(1, tags(&[("highway", "path")])));
+		osm.merge_downloaded(data(2, tags(&[("highway", "footway")])));
+		assert_eq!(osm.data.ways[&10].version, 2);
+		assert_eq!(osm.data.ways[&10].tags.get("highway").unwrap(), "footway");
+		assert_eq!(osm.data.nodes[&1].version, 2);
+
+		// older versions are ignored
+		osm.merge_downloaded(data(1, tags(&[("highway", "path")])));
+		assert_eq!(osm.data.ways[&10].version, 2);
+		assert!(osm.changes.is_empty() && osm.conflicts.is_empty());
+	}
+
+	#[test]
+	fn rebase() {
+		let mut osm = edited(tags(&[("highway", "path"), ("surface", "gravel"), ("name", "Schulweg")]));
+		osm.merge_downloaded(data(2, tags(&[("highway", "path"), ("surface", "asphalt")])));
+		assert!(osm.conflicts.is_empty());
+
+		let way = &osm.data.ways[&10];
+		assert_eq!(way.version, 2);
+		assert_eq!(way.tags, tags(&[("highway", "path"), ("surface", "asphalt"), ("name", "Schulweg")]));
+
+		// the edit is now based on the new version
+		assert!(matches!(osm.changes.as_slice(), [Change::UpdateWay(_, way)] if way.id == 10 && way.version == 2));
+		assert_eq!(osm.base[&ElementId::Way(10)].version(), 2);
+	}
+
+	#[test]
+	fn rebase_keeps_history() {
+		let mut osm = edited(tags(&[("highway", "path"), ("surface", "gravel"), ("name", "Schulweg")]));
+		let lit = tags(&[("highway", "path"), ("surface", "gravel"), ("name", "Schulweg"), ("lit", "yes")]);
+		osm.apply_change(Change::UpdateWay(osm.data.ways[&10].clone(), way(1, lit)));
+		osm.apply_change(Change::CreateNode(node(Id::MAX, 0)));
+		assert!(osm.undo() && osm.undo());
+
+		osm.merge_downloaded(data(2, tags(&[("highway", "path"), ("surface", "asphalt")])));
+		assert!(osm.conflicts.is_empty());
+		assert_eq!(osm.changes.len(), 1);
+		assert_eq!(osm.undone.len(), 2);
+
+		// the undone edit is based on the new version as well
+		assert!(osm.redo());
+		let way = &osm.data.ways[&10];
+		assert_eq!(way.version, 2);
+		assert_eq!(way.tags, tags(&[("highway", "path"), ("surface", "asphalt"), ("name", "Schulweg"), ("lit", "yes")]));
+
+		// undoing every edit leads back to the server version
+		assert!(osm.undo() && osm.undo());
+		assert_eq!(osm.data.ways[&10].tags, tags(&[("highway", "path"), ("surface", "asphalt")]));
+		assert!(osm.base.is_empty());
+	}
+
+	#[test]
+	fn conflict() {
+		let local = tags(&[("highway", "path"), ("surface", "compacted")]);
+		let mut osm = edited(local.clone());
+		osm.merge_downloaded(data(2, tags(&[("highway", "path"), ("surface", "asphalt")])));
+		assert_eq!(osm.conflicts.len(), 1);
+		assert_eq!(osm.conflicts[0].tags[0].key, "surface");
+
+		// the local version is kept until the conflict is resolved
+		assert_eq!(osm.data.ways[&10].tags, local);
+		assert_eq!(osm.data.nodes[&1].version, 2);
+
+		// downloading the same version again does not reset the conflict
+		osm.conflicts[0].tags[0].choice = Side::Server;
+		osm.merge_downloaded(data(2, tags(&[("highway", "path"), ("surface", "asphalt")])));
+		assert_eq!(osm.conflicts.len(), 1);
+
+		let conflict = osm.conflicts.remove(0);
+		osm.resolve_conflict(&conflict);
+		assert_eq!(osm.data.ways[&10].tags.get("surface").unwrap(), "asphalt");
+		assert!(osm.changes.is_empty());
+	}
+
+	#[test]
+	fn undo_redo() {
+		let mut osm = EditorOsmData::default();
+		osm.merge_downloaded(data(1, tags(&[("highway", "path")])));
+		let original = osm.data.ways[&10].clone();
+
+		osm.apply_change(Change::UpdateWay(original.clone(), way(1, tags(&[("highway", "footway")]))));
+		osm.apply_change(Change::DeleteNode(node(1, 1)));
+
+		assert!(osm.undo());
+		assert!(osm.data.nodes.contains_key(&1));
+
+		// only the caches depending on tags are refreshed
+		osm.cache_flags = 0;
+		osm.refresh_in_view_flag = false;
+		assert!(osm.undo());
+		assert_eq!(osm.data.ways[&10].tags, original.tags);
+		assert_ne!(osm.cache_flags & CacheFlag::WayArea as u8, 0);
+		assert_eq!(osm.cache_flags & (CacheFlag::NodeProjection as u8 | CacheFlag::NodeUsage as u8), 0);
+		assert!(!osm.refresh_in_view_flag);
+
+		// nothing is edited anymore
+		assert!(!osm.undo());
+		assert!(osm.changes.is_empty() && osm.base.is_empty());
+		assert_eq!(osm.undone.len(), 2);
+
+		assert!(osm.redo());
+		assert_eq!(osm.data.ways[&10].tags.get("highway").unwrap(), "footway");
+		assert_eq!(osm.base[&ElementId::Way(10)].version(), 1);
+
+		// a new change discards the undone ones
+		osm.apply_change(Change::CreateNode(node(Id::MAX, 0)));
+		assert!(osm.undone.is_empty() && !osm.redo());
+		assert!(osm.undo() && !osm.data.nodes.contains_key(&Id::MAX));
+	}
+
+	#[test]
+	fn update_node_tags() {
+		let mut osm = EditorOsmData::default();
+		osm.merge_downloaded(data(1, Tags::default()));
+		osm.cache_flags = 0;
+		osm.refresh_in_view_flag = false;
+
+		let node = ElementRef::Node(&osm.data.nodes[&1]);
+		let change = Change::update_tags(&node, tags(&[("amenity", "bench")]));
+		osm.apply_change(change);
+		assert_eq!(osm.data.nodes[&1].tags.get("amenity").unwrap(), "bench");
+		assert_eq!(osm.base[&ElementId::Node(1)].version(), 1);
+
+		// nothing cached depends on the tags of nodes
+		assert_eq!(osm.cache_flags, 0);
+		assert!(!osm.refresh_in_view_flag);
+
+		assert!(osm.undo());
+		assert!(osm.data.nodes[&1].tags.is_empty());
+	}
+
+	#[test]
+	fn drag_node() {
+		let mut osm = EditorOsmData::default();
+		osm.merge_downloaded(data(1, Tags::default()));
+		osm.cache_flags = 0;
+		osm.refresh_in_view_flag = false;
+
+		let original = osm.data.nodes[&1].clone();
+		osm.drag_node(1, Coordinate::new(50.5, 10.5), Pos2::ZERO);
+		osm.record_change(Change::UpdateNode(original, osm.data.nodes[&1].clone()));
+
+		// the spatial index is updated without a refresh
+		assert_eq!(osm.cache_flags, 0);
+		assert!(!osm.refresh_in_view_flag);
+		let at = |osm: &EditorOsmData, lat: f32, lon: f32| (
+			osm.rtree_data.nodes.locate_all_at_point(&[lat, lon]).map(|x| x.data).collect::<Vec<_>>(),
+			osm.rtree_data.ways.locate_all_at_point(&[lat, lon]).map(|x| x.data).collect::<Vec<_>>(),
+		);
+		assert_eq!(at(&osm, 50.5, 10.5), (vec![1], vec![10]));
+		assert_eq!(osm.base[&ElementId::Node(1)].version(), 1);
+
+		// undoing moves it back, the nodes are projected again
+		assert!(osm.undo());
+		assert!(at(&osm, 50.5, 10.5).0.is_empty() && at(&osm, 50.5, 10.5).1.is_empty());
+		assert_eq!(at(&osm, 50.0, 10.0).0.len(), 2);
+		assert_ne!(osm.cache_flags & CacheFlag::NodeProjection as u8, 0);
+		assert!(!osm.refresh_in_view_flag);
+	}
+
+	#[test]
+	fn create_node() {
+		let mut osm = EditorOsmData::default();
+		osm.merge_downloaded(data(1, Tags::default()));
+		let id = osm.create_node(Coordinate::new(50.1, 10.1), tags(&[("amenity", "bench")]));
+		assert!(is_placeholder_id(id));
+		assert!(osm.refresh_in_view_flag);
+
+		// the new node is drawn as an orphan
+		osm.refresh_elements_in_view(&AABB::from_corners([49.0, 9.0], [51.0, 11.0]));
+		osm.refresh_orphan_nodes_cache();
+		osm.refresh_way_area_cache();
+		osm.refresh_node_dedup_cache();
+		assert!(osm.orphan_nodes.contains(&id) && osm.node_dedup.orphan_nodes.contains(&id));
+
+		let create = OsmChange::from(&osm.changes).create.expect("create block missing");
+		assert_eq!(create.node.len(), 1);
+		assert_eq!(create.node[0].id, -1);
+		assert_eq!(create.node[0].tags[0].v, "bench");
+	}
+
+	#[test]
+	fn draw_way() {
+		let mut osm = EditorOsmData::default();
+		osm.merge_downloaded(data(1, tags(&[("highway", "path")])));
+		let pos = |lat: f64| Coordinate::new(lat, 10.0);
+
+		osm.data.nodes.get_mut(&2).unwrap().pos = pos(51.0);
+
+		// snapped to the existing way
+		let first = osm.create_node(pos(50.4), Tags::default());
+		assert!(osm.insert_node_into_way(10, [1, 2], first));
+		assert_eq!(osm.data.ways[&10].nodes, [1, first, 2]);
+		assert!(!osm.insert_node_into_way(10, [2, 1], first));
+
+		let second = osm.create_node(pos(50.1), Tags::default());
+		let third = osm.create_node(pos(50.2), Tags::default());
+		let id = osm.create_way(vec![first, second, third], tags(&[("building", "yes")]));
+
+		// closing the ring turns it into an area
+		let mut nodes = osm.data.ways[&id].nodes.clone();
+		nodes.push(first);
+		osm.set_way_nodes(id, nodes);
+		osm.refresh_elements_in_view(&AABB::from_corners([49.0, 9.0], [51.0, 11.0]));
+		osm.refresh_way_area_cache();
+		assert!(osm.way_area.areas.contains(&id) && osm.way_area.ways.contains(&10));
+
+		let osmchange = OsmChange::from(&osm.changes);
+		let create = osmchange.create.expect("create block missing");
+		assert_eq!(create.node.len(), 3);
+		assert_eq!(create.way.len(), 1);
+		assert_eq!(create.way[0].nodes.iter().map(|x| x.r#ref).collect::<Vec<_>>(), [-1, -2, -3, -1]);
+
+		let modify = osmchange.modify.expect("modify block missing");
+		assert_eq!(modify.way[0].nodes.iter().map(|x| x.r#ref).collect::<Vec<_>>(), [1, -1, 2]);
+
+		// the segment was split by the first node, another point snapped to it goes into the closest part
+		let split = DrawTarget::Segment(10, [1, 2], Position::new(10.0, 50.7)).resolve(&mut osm);
+		assert_eq!(osm.data.ways[&10].nodes, [1, first, split, 2]);
+	}
+
+	#[test]
+	fn deleted_locally() {
+		let mut osm = EditorOsmData::default();
+		osm.merge_downloaded(data(1, Tags::default()));
+		osm.apply_change(Change::DeleteWay(way(1, Tags::default())));
+
+		// unchanged on the server, the deletion is kept
+		osm.merge_downloaded(data(1, Tags::default()));
+		assert!(!osm.data.ways.contains_key(&10) && osm.conflicts.is_empty());
+
+		osm.merge_downloaded(data(2, tags(&[("highway", "path")])));
+		assert_eq!(osm.conflicts.len(), 1);
+		assert!(osm.conflicts[0].deletion.is_some());
+		assert!(!osm.data.ways.contains_key(&10));
+	}
+}
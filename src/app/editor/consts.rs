@@ -12,7 +12,9 @@ pub const HOVER_TOOLTIP_OFFSET: Vec2 = Vec2::splat(16.0);
 pub const HOVER_TOOLTIP_COLOR: Color32 = Color32::from_black_alpha(200);
 pub const HOVER_TOOLTIP_FONT_SIZE: f32 = 14.0;
 
-pub const MAX_DOWNLOAD_AREA: f64 = 0.0005;
+pub const MAX_DOWNLOAD_TILES: usize = 64; // tiles of a single download, the download is disabled for larger areas
+pub const AUTO_DOWNLOAD_MIN_ZOOM: f64 = 16.0;
+pub const AUTO_DOWNLOAD_ERROR_PAUSE_SECONDS: f64 = 30.0; // failed or cancelled downloads are not retried right away
 pub const NODE_MIN_ZOOM: f64 = 17.0;
 
 const TINT_DARK: u8 = 222;
@@ -26,6 +28,39 @@ pub const PARTIAL_FILL_THRESHOLD: f64 = 18.0;
 
 pub const DOWNLOAD_FEEDBACK_SECONDS: f64 = 3.0;
 
+pub const MAX_TAG_VALUE_LENGTH: usize = 255;
+
+// name, key and value of the points that can be placed, further tags are added in the tag editor
+pub const POI_PRESETS: [(&str, &str, &str); 8] = [
+	("Bench", "amenity", "bench"),
+	("Waste Basket", "amenity", "waste_basket"),
+	("Bicycle Parking", "amenity", "bicycle_parking"),
+	("Drinking Water", "amenity", "drinking_water"),
+	("Post Box", "amenity", "post_box"),
+	("Street Lamp", "highway", "street_lamp"),
+	("Tree", "natural", "tree"),
+	("Shop", "shop", "yes"),
+];
+
+// same as POI_PRESETS for drawn ways, closed ones with area tags are drawn as areas
+pub const WAY_PRESETS: [(&str, &str, &str); 8] = [
+	("Footway", "highway", "footway"),
+	("Path", "highway", "path"),
+	("Service Road", "highway", "service"),
+	("Residential Road", "highway", "residential"),
+	("Building", "building", "yes"),
+	("Parking", "amenity", "parking"),
+	("Grass", "landuse", "grass"),
+	("Playground", "leisure", "playground"),
+];
+
+pub const SNAP_DISTANCE: f32 = 8.0; // drawn points snap to nodes and ways closer than this
+
+// keys for eframe::Storage
+pub const STORAGE_SERVERS: &str = "servers";
+pub const STORAGE_TARGET_SERVER: &str = "target_server";
+pub const STORAGE_ACCOUNTS: &str = "accounts";
+
 const fn tint(dark: bool) -> u8 {
 	if dark { TINT_DARK } else { TINT_LIGHT }
 }
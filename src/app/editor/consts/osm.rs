@@ -17,6 +17,9 @@ pub const HOVER_SIZE_INCREASE: f32 = 2.0;
 pub const SELECTION_COLOR: Color32 = Color32::from_rgb(40, 180, 255);
 pub const SELECTION_SIZE_INCREASE: f32 = 2.5;
 
+pub const LOADED_AREA_WIDTH: f32 = 2.0;
+pub const LOADED_AREA_COLOR: Color32 = Color32::from_rgba_premultiplied(120, 60, 0, 160);
+
 pub const PATH_WIDTH: f32 = 2.5;
 pub const SERVICE_ROAD_WIDTH: f32 = 4.0;
 pub const MINOR_ROAD_WIDTH: f32 = 5.0;
@@ -4,3 +4,8 @@ const CTRL_SHIFT: Modifiers = Modifiers { ctrl: true, shift: true, alt: false, c
 
 pub static DOWNLOAD: &KeyboardShortcut = &KeyboardShortcut::new(CTRL_SHIFT, Key::ArrowDown);
 pub static WIREFRAME: &KeyboardShortcut = &KeyboardShortcut::new(Modifiers::NONE, Key::W);
+pub static UNDO: &KeyboardShortcut = &KeyboardShortcut::new(Modifiers::CTRL, Key::Z);
+pub static REDO: &KeyboardShortcut = &KeyboardShortcut::new(CTRL_SHIFT, Key::Z);
+pub static ADD_POI: &KeyboardShortcut = &KeyboardShortcut::new(Modifiers::NONE, Key::P);
+pub static DRAW_WAY: &KeyboardShortcut = &KeyboardShortcut::new(Modifiers::NONE, Key::L);
+pub static CANCEL_TOOL: &KeyboardShortcut = &KeyboardShortcut::new(Modifiers::NONE, Key::Escape);
@@ -0,0 +1,204 @@
+use super::cache::{Change, Element, ElementId};
+use osm_parser::{Coordinate, Id, Node, Tags, Way};
+use std::collections::BTreeSet;
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Side {
+	#[default]
+	Local,
+	Server,
+}
+
+#[derive(Debug)]
+pub struct TagConflict {
+	pub key: String,
+	pub local: Option<String>,
+	pub server: Option<String>,
+	pub choice: Side,
+}
+
+// Three-way merge of a locally changed element with a newer version from the server.
+// Only the parts that were changed on both sides have to be resolved by the user.
+#[derive(Debug)]
+pub struct Conflict {
+	pub element: ElementId,
+	pub local: Option<Element>, // None if deleted locally
+	pub server: Element,
+	pub tags: Vec<TagConflict>,
+	pub geometry: Option<Side>, // Some if the geometry was changed on both sides
+	pub deletion: Option<Side>, // Some if deleted locally, but modified on the server
+	merged_tags: Tags,
+	merged_geometry: Side,
+}
+
+#[derive(PartialEq)]
+enum Geometry<'a> {
+	Node(f64, f64),
+	Way(&'a [Id]),
+}
+
+fn geometry(element: &Element) -> Geometry<'_> {
+	match element {
+		Element::Node(n) => Geometry::Node(n.pos.lat, n.pos.lon),
+		Element::Way(w) => Geometry::Way(&w.nodes),
+	}
+}
+
+fn tags(element: &Element) -> &Tags {
+	match element {
+		Element::Node(n) => &n.tags,
+		Element::Way(w) => &w.tags,
+	}
+}
+
+// Returns the side whose value should be kept, or None if both sides changed the value differently.
+fn merge_value<T: PartialEq>(base: T, local: T, server: T) -> Option<Side> {
+	if local == server || local == base {
+		Some(Side::Server)
+	} else if server == base {
+		Some(Side::Local)
+	} else {
+		None
+	}
+}
+
+impl Conflict {
+	pub fn new(base: &Element, local: Option<Element>, server: Element) -> Self {
+		let mut conflict = Self {
+			element: server.element_ref().element_id(),
+			local: None,
+			server,
+			tags: Vec::new(),
+			geometry: None,
+			deletion: None,
+			merged_tags: Tags::default(),
+			merged_geometry: Side::Server,
+		};
+
+		let Some(local) = local else {
+			conflict.deletion = Some(Side::Local);
+			return conflict;
+		};
+
+		/* tags */ {
+			let (base, local, server) = (tags(base), tags(&local), tags(&conflict.server));
+			let keys = base.keys().chain(local.keys()).chain(server.keys()).collect::<BTreeSet<_>>();
+
+			for key in keys {
+				let (b, l, s) = (base.get(key), local.get(key), server.get(key));
+				match merge_value(b, l, s) {
+					Some(Side::Local) => if let Some(v) = l { conflict.merged_tags.insert(key.to_owned(), v.to_owned()); },
+					Some(Side::Server) => if let Some(v) = s { conflict.merged_tags.insert(key.to_owned(), v.to_owned()); },
+					None => conflict.tags.push(TagConflict {
+						key: key.to_owned(),
+						local: l.cloned(),
+						server: s.cloned(),
+						choice: Side::Local,
+					}),
+				}
+			}
+		}
+
+		/* geometry */ {
+			match merge_value(geometry(base), geometry(&local), geometry(&conflict.server)) {
+				Some(side) => conflict.merged_geometry = side,
+				None => conflict.geometry = Some(Side::Local),
+			}
+		}
+
+		conflict.local = Some(local);
+		conflict
+	}
+
+	// Whether the merge needs no decisions of the user, so the local changes can be rebased onto the server version.
+	pub const fn is_clean(&self) -> bool {
+		self.tags.is_empty() && self.geometry.is_none() && self.deletion.is_none()
+	}
+
+	// Returns the server version and the change to apply on top of it, if any.
+	pub fn resolve(&self) -> (Element, Option<Change>) {
+		let server = self.server.clone();
+
+		if let Some(side) = self.deletion {
+			let change = match side {
+				Side::Local => Some(match &server {
+					Element::Node(n) => Change::DeleteNode(n.clone()),
+					Element::Way(w) => Change::DeleteWay(w.clone()),
+				}),
+				Side::Server => None,
+			};
+			return (server, change);
+		}
+
+		let mut tags = self.merged_tags.clone();
+		for tag in &self.tags {
+			let value = match tag.choice {
+				Side::Local => &tag.local,
+				Side::Server => &tag.server,
+			};
+			if let Some(v) = value {
+				tags.insert(tag.key.clone(), v.clone());
+			}
+		}
+
+		let geometry_side = self.geometry.unwrap_or(self.merged_geometry);
+
+		let change = match (&server, &self.local) {
+			(Element::Way(server_way), Some(Element::Way(local_way))) => {
+				let mut way = server_way.clone();
+				way.tags = tags;
+				if geometry_side == Side::Local {
+					way.nodes.clone_from(&local_way.nodes);
+				}
+
+				(way.tags != server_way.tags || way.nodes != server_way.nodes)
+					.then(|| Change::UpdateWay(server_way.clone(), way))
+			}
+			(Element::Node(server_node), Some(Element::Node(local_node))) => {
+				let mut node = server_node.clone();
+				node.tags = tags;
+				if geometry_side == Side::Local {
+					node.pos = Coordinate::new(local_node.pos.lat, local_node.pos.lon);
+				}
+
+				(node.tags != server_node.tags || (node.pos.lat, node.pos.lon) != (server_node.pos.lat, server_node.pos.lon))
+					.then(|| Change::UpdateNode(server_node.clone(), node))
+			}
+			_ => None,
+		};
+
+		(server, change)
+	}
+}
+
+// Returns the server version with the changes of local compared to base on top, local wins where both sides changed.
+pub fn rebase_node(base: &Node, local: &Node, server: &Node) -> Node {
+	let mut node = server.clone();
+	node.tags = rebase_tags(&base.tags, &local.tags, &server.tags);
+	if (local.pos.lat, local.pos.lon) != (base.pos.lat, base.pos.lon) {
+		node.pos = Coordinate::new(local.pos.lat, local.pos.lon);
+	}
+	node
+}
+
+// Same as rebase_node for ways.
+pub fn rebase_way(base: &Way, local: &Way, server: &Way) -> Way {
+	let mut way = server.clone();
+	way.tags = rebase_tags(&base.tags, &local.tags, &server.tags);
+	if local.nodes != base.nodes {
+		way.nodes.clone_from(&local.nodes);
+	}
+	way
+}
+
+fn rebase_tags(base: &Tags, local: &Tags, server: &Tags) -> Tags {
+	let mut tags = server.clone();
+	for key in base.keys().chain(local.keys()).collect::<BTreeSet<_>>() {
+		match (base.get(key), local.get(key)) {
+			(b, l) if b == l => {},
+			(_, Some(v)) => { tags.insert(key.to_owned(), v.to_owned()); },
+			(_, None) => { tags.remove(key); },
+		}
+	}
+	tags
+}
@@ -1,4 +1,4 @@
-use osm_parser::{Id, OsmData};
+use osm_parser::{Coordinate, Id, OsmData};
 use rstar::primitives::{GeomWithData, Rectangle};
 use rstar::{RTree, AABB};
 use rustc_hash::FxHashMap;
@@ -18,11 +18,9 @@ pub struct RStarOsmData {
 
 impl From<&OsmData> for RStarOsmData {
 	fn from(data: &OsmData) -> Self {
-		#[allow(clippy::cast_possible_truncation)]
 		let mut positions = data.nodes.iter()
-			.map(|(id, node)| {
-				(*id, WebMercatorPoint::from([node.pos.lat as f32, node.pos.lon as f32]))
-			}).collect::<FxHashMap<Id, WebMercatorPoint>>();
+			.map(|(id, node)| (*id, point(&node.pos)))
+			.collect::<FxHashMap<Id, WebMercatorPoint>>();
 
 		let ways = RTreeWays::bulk_load(
 			data.ways.iter().map(|(id, way)| {
@@ -39,3 +37,35 @@ impl From<&OsmData> for RStarOsmData {
 		Self { nodes, ways }
 	}
 }
+
+impl RStarOsmData {
+	// Moves the entry of a node whose position in data changed, the envelopes of the ways using it are updated as well.
+	pub fn move_node(&mut self, data: &OsmData, id: Id, from: &Coordinate) {
+		let from = point(from);
+		let Some(node) = data.nodes.get(&id) else { return; };
+
+		self.nodes.remove(&NodeEntry::new(from, id));
+		self.nodes.insert(NodeEntry::new(point(&node.pos), id));
+
+		// the envelopes of the ways using the node still contain its previous position
+		let entries = self.ways.locate_all_at_point(&from)
+			.filter(|x| data.ways.get(&x.data).is_some_and(|way| way.nodes.contains(&id)))
+			.copied()
+			.collect::<Vec<_>>();
+
+		for entry in entries {
+			self.ways.remove(&entry);
+
+			let points = data.ways[&entry.data].nodes.iter()
+				.filter_map(|x| data.nodes.get(x))
+				.map(|x| point(&x.pos))
+				.collect::<Vec<_>>();
+			self.ways.insert(WayEntry::new(Rectangle::from_aabb(AABB::from_points(&points)), entry.data));
+		}
+	}
+}
+
+#[allow(clippy::cast_possible_truncation)]
+const fn point(pos: &Coordinate) -> WebMercatorPoint {
+	[pos.lat as f32, pos.lon as f32]
+}
@@ -1,14 +1,16 @@
-use super::{cache::EditorOsmData, visual::Visualization, EditorPluginState, FillMode};
+use super::{cache::{EditorOsmData, ElementId}, consts::MAX_TAG_VALUE_LENGTH, merge::Conflict, visual::Visualization, EditorPluginState, FillMode};
 use crate::app::osm::TargetServer;
 use crate::app::{
-	osm::{OsmResult, OsmToken},
-	osmchange::OsmChange,
+	osm::{Accounts, MapProgress, OsmApiError, OsmResult, Tile},
+	osmchange::{DiffResult, OsmChange, Tag},
 	providers::{Provider, ProviderMap, TilesKind},
 	windows::WindowBitflag,
+	worker::RequestId,
 };
 use eframe::egui::Vec2;
+use osm_parser::Tags;
 use std::{
-	collections::HashMap,
+	collections::{HashMap, HashSet},
 	fmt::{Display, Formatter},
 	num::NonZeroU32
 };
@@ -20,6 +22,7 @@ pub struct EditorState {
 	pub map_state: MapState,
 	pub plugin_state: EditorPluginState,
 	pub osm_data: EditorOsmData,
+	pub tag_editor: TagEditorState,
 	pub window_flags: WindowBitflag,
 	pub prev_size: Vec2,
 }
@@ -35,15 +38,43 @@ impl EditorState {
 				selected_fill_mode: FillMode::default(),
 				selection_mode: SelectionFlag::Nodes as u8 + SelectionFlag::Ways as u8,
 				download: MapDownloadState::Idle(None),
+				used_providers: Vec::new(),
 				scale_factor: 1.0,
 				zoom_with_ctrl: false,
+				auto_download: true,
+				downloaded_tiles: HashSet::new(),
+				tool: Tool::default(),
+				poi_preset: 0,
+				way_preset: 0,
 			},
 			osm_data: EditorOsmData::default(),
 			plugin_state: EditorPluginState::default(),
+			tag_editor: TagEditorState::default(),
 			window_flags: WindowBitflag::default(),
 			prev_size: Vec2::ZERO,
 		}
 	}
+
+	pub fn undo(&mut self) {
+		if self.osm_data.undo() {
+			self.plugin_state.forget_missing(&self.osm_data);
+		}
+	}
+
+	pub fn redo(&mut self) {
+		if self.osm_data.redo() {
+			self.plugin_state.forget_missing(&self.osm_data);
+		}
+	}
+
+	// Elements of another server are unrelated, so the downloaded data and the edits of it are dropped.
+	pub fn clear_osm_data(&mut self) {
+		self.osm_data = EditorOsmData::default();
+		self.map_state.downloaded_tiles.clear();
+		self.map_state.download = MapDownloadState::Idle(None);
+		self.map_state.used_providers.clear();
+		self.plugin_state.forget_missing(&self.osm_data);
+	}
 }
 
 pub struct MapState {
@@ -52,8 +83,30 @@ pub struct MapState {
 	pub selected_fill_mode: FillMode,
 	pub selection_mode: SelectionBitflag,
 	pub download: MapDownloadState,
+	pub used_providers: Vec<Provider>, // providers that were selected while editing, for imagery_used
 	pub scale_factor: f32,
 	pub zoom_with_ctrl: bool,
+	pub auto_download: bool, // download missing tiles in view while panning
+	pub downloaded_tiles: HashSet<Tile>,
+	pub tool: Tool,
+	pub poi_preset: usize, // index into POI_PRESETS
+	pub way_preset: usize, // index into WAY_PRESETS
+}
+
+impl MapState {
+	// The progress of the running download, if the response belongs to it.
+	pub const fn download_progress(&mut self, request: RequestId) -> Option<&mut DownloadProgress> {
+		match &mut self.download {
+			MapDownloadState::Downloading(progress) if progress.request == request => Some(progress),
+			_ => None,
+		}
+	}
+
+	pub fn record_used_provider(&mut self) {
+		if let Some(provider) = self.selected_provider && !self.used_providers.contains(&provider) {
+			self.used_providers.push(provider);
+		}
+	}
 }
 
 pub type SelectionBitflag = u8;
@@ -80,6 +133,15 @@ impl SelectionFlag {
 	pub const ITER: [Self; 3] = [Self::Nodes, Self::Ways, Self::Areas];
 }
 
+// Decides what a click on the map does.
+#[derive(Copy, Clone, PartialEq, Eq, Default)]
+pub enum Tool {
+	#[default]
+	Select,
+	AddPoi,
+	DrawWay,
+}
+
 pub type CacheBitflag = u8;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -114,20 +176,251 @@ impl CacheFlag {
 
 pub enum MapDownloadState {
 	Idle(Option<(OsmResult<()>, f64)>),
-	Downloading,
+	Downloading(DownloadProgress),
+}
+
+pub struct DownloadProgress {
+	pub request: RequestId, // responses of other downloads only add their data
+	pub cancelled: bool,
+	pub tiles: usize, // finished tiles
+	pub total_tiles: usize,
+	pub current: Option<MapProgress>, // latest progress of a tile in flight
+	pub retry: Option<(OsmApiError, f64)>, // error of the previous attempt and the time of the next one
+}
+
+impl DownloadProgress {
+	pub const fn new(request: RequestId, total_tiles: usize) -> Self {
+		Self { request, cancelled: false, tiles: 0, total_tiles, current: None, retry: None }
+	}
+
+	// Byte progress is only meaningful for a single tile.
+	#[allow(clippy::cast_precision_loss)]
+	pub fn fraction(&self) -> Option<f32> {
+		if self.total_tiles > 1 {
+			Some(self.tiles as f32 / self.total_tiles as f32)
+		} else {
+			self.current.and_then(|x| x.fraction())
+		}
+	}
+}
+
+// Tags of the selected element while they are edited, they are applied once a field loses focus.
+#[derive(Default)]
+pub struct TagEditorState {
+	pub element: Option<ElementId>,
+	pub source: Tags, // tags the rows were loaded from
+	pub rows: Vec<(String, String)>,
+	pub text: String, // one key=value pair per line
+	pub text_view: bool,
+}
+
+impl TagEditorState {
+	// Reloads the rows when another element was selected or its tags were changed, for example by undo.
+	pub fn load(&mut self, element: &ElementId, tags: &Tags) {
+		if self.element.as_ref() == Some(element) && self.source == *tags { return; }
+
+		self.element = Some(element.clone());
+		self.source = tags.clone();
+		self.set_tags(tags);
+	}
+
+	// Edits that were not applied yet are carried over to the other view.
+	pub fn switch_view(&mut self, text_view: bool) {
+		if self.text_view == text_view { return; }
+
+		self.set_tags(&self.edited_tags());
+		self.text_view = text_view;
+	}
+
+	fn set_tags(&mut self, tags: &Tags) {
+		self.rows = tags.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+		self.rows.sort_unstable();
+		self.text = self.rows.iter().map(|(k, v)| format!("{k}={v}\n")).collect();
+	}
+
+	// Returns the edits that were not applied yet when another element was selected, usually because the click deselected a focused field.
+	// Invalid edits are dropped.
+	pub fn take_pending(&mut self, selected: Option<&ElementId>) -> Option<(ElementId, Tags)> {
+		if self.element.as_ref() == selected { return None; }
+
+		let tags = self.edited_tags();
+		let valid = self.is_valid();
+		self.element.take().filter(|_| valid && tags != self.source).map(|id| (id, tags))
+	}
+
+	pub fn edited_tags(&self) -> Tags {
+		tags_from_pairs(self.pairs())
+	}
+
+	// The API rejects keys and values that are too long.
+	pub fn is_valid(&self) -> bool {
+		self.pairs().all(|(k, v)| k.trim().chars().count() <= MAX_TAG_VALUE_LENGTH && v.trim().chars().count() <= MAX_TAG_VALUE_LENGTH)
+	}
+
+	fn pairs(&self) -> Box<dyn Iterator<Item = (&str, &str)> + '_> {
+		if self.text_view {
+			// lines without "=" are ignored
+			Box::new(self.text.lines().filter_map(|x| x.split_once('=')))
+		} else {
+			Box::new(self.rows.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+		}
+	}
+}
+
+// Rows with an empty key or value are left out, the last value of a key wins.
+fn tags_from_pairs<'a>(pairs: impl Iterator<Item = (&'a str, &'a str)>) -> Tags {
+	let mut tags = Tags::default();
+	for (k, v) in pairs {
+		let (k, v) = (k.trim(), v.trim());
+		if !k.is_empty() && !v.is_empty() {
+			tags.insert(k.to_owned(), v.to_owned());
+		}
+	}
+	tags
+}
+
+#[derive(Default)]
+pub struct ChangesetForm {
+	pub comment: String,
+	pub source: String,
+	pub hashtags: String,
+}
+
+impl ChangesetForm {
+	// Normalizes user input like "#a b, #c" into "#a;#b;#c".
+	pub fn hashtags(&self) -> String {
+		self.hashtags
+			.split(|c: char| c.is_whitespace() || c == ',' || c == ';')
+			.map(|x| x.trim_start_matches('#'))
+			.filter(|x| !x.is_empty())
+			.map(|x| format!("#{x}"))
+			.collect::<Vec<_>>()
+			.join(";")
+	}
+
+	pub fn is_comment_empty(&self) -> bool {
+		self.comment.trim().is_empty()
+	}
+
+	pub fn is_valid(&self) -> bool {
+		!self.is_comment_empty()
+			&& self.comment.chars().count() <= MAX_TAG_VALUE_LENGTH
+			&& self.source.chars().count() <= MAX_TAG_VALUE_LENGTH
+			&& self.hashtags().chars().count() <= MAX_TAG_VALUE_LENGTH
+	}
+
+	pub fn tags(&self, used_providers: &[Provider]) -> Vec<Tag> {
+		let mut tags = vec![
+			Tag { k: "created_by".into(), v: crate::USER_AGENT.into() },
+			Tag { k: "comment".into(), v: self.comment.trim().into() },
+		];
+
+		if !self.source.trim().is_empty() {
+			tags.push(Tag { k: "source".into(), v: self.source.trim().into() });
+		}
+
+		let hashtags = self.hashtags();
+		if !hashtags.is_empty() {
+			tags.push(Tag { k: "hashtags".into(), v: hashtags });
+		}
+
+		if !used_providers.is_empty() {
+			let imagery = used_providers.iter()
+				.map(|x| x.imagery_name())
+				.collect::<Vec<_>>()
+				.join(";");
+			tags.push(Tag { k: "imagery_used".into(), v: imagery });
+		}
+
+		tags
+	}
+}
+
+#[derive(Default)]
+pub struct ServerForm {
+	pub name: String,
+	pub url: String,
+	pub client_id: String,
+}
+
+impl ServerForm {
+	// Accepts "host[:port]" with an optional "http://" or "https://" prefix, https is used if none is given.
+	pub fn target_server(&self) -> Option<TargetServer> {
+		let url = self.url.trim().trim_end_matches('/');
+		let (https, host) = if let Some(host) = url.strip_prefix("http://") {
+			(false, host)
+		} else {
+			(true, url.strip_prefix("https://").unwrap_or(url))
+		};
+
+		let name = self.name.trim();
+		let client_id = self.client_id.trim();
+		if name.is_empty() || host.is_empty() || host.contains('/') || client_id.is_empty() {
+			return None;
+		}
+
+		Some(TargetServer {
+			name: name.into(),
+			host: host.into(),
+			client_id: client_id.into(),
+			https,
+		})
+	}
 }
 
 #[derive(Default)]
 pub struct UploaderState {
+	pub form: ChangesetForm,
 	pub osmchange: OsmChange,
 	pub osmchange_text: String,
+	pub upload_pending: bool,
 	pub changeset_creation: Option<OsmResult<NonZeroU32>>,
+	pub changeset_upload: Option<OsmResult<DiffResult>>,
+	pub changeset_close: Option<OsmResult<NonZeroU32>>,
+	pub conflict: Option<OsmResult<Conflict>>,
+}
+
+impl UploaderState {
+	pub fn reset_progress(&mut self) {
+		self.changeset_creation = None;
+		self.changeset_upload = None;
+		self.changeset_close = None;
+		self.conflict = None;
+	}
 }
 
 #[derive(Default)]
 pub struct AuthenticatorState {
-	// todo: currently no way to check which server this belongs to
-	pub token: HashMap<TargetServer, OsmResult<OsmToken>>,
+	pub accounts: HashMap<String, Accounts>, // by base url of the server
+	pub error: HashMap<String, OsmApiError>, // last login or validation error, by base url of the server
+	pub validated: HashSet<String>, // base urls of servers whose accounts were validated since the start
 	pub authorization_code: String,
+	#[cfg(not(target_family = "wasm"))]
+	pub authorize_url: Option<String>, // set while waiting for the loopback redirect
 	pub request_pending: bool,
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn tag_value_length() {
+		let mut tags = Tags::default();
+		tags.insert("amenity".into(), "bench".into());
+
+		let mut state = TagEditorState::default();
+		state.load(&ElementId::Node(1), &tags);
+		state.rows.push(("name".into(), "x".repeat(MAX_TAG_VALUE_LENGTH + 1)));
+		assert!(!state.is_valid());
+
+		// the edits are not applied when another element is selected
+		assert!(state.take_pending(None).is_none());
+
+		state.load(&ElementId::Node(1), &tags);
+		state.switch_view(true);
+		state.text.push_str(&format!("name={}\n", "x".repeat(MAX_TAG_VALUE_LENGTH)));
+		assert!(state.is_valid());
+		assert_eq!(state.take_pending(None).map(|(_, tags)| tags.len()), Some(2));
+	}
+}
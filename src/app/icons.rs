@@ -9,3 +9,5 @@ pub const DOWNLOAD: ImageSource = include_image!("../../assets/ui/download.svg")
 pub const UPLOAD: ImageSource = include_image!("../../assets/ui/upload.svg");
 pub const USER: ImageSource = include_image!("../../assets/ui/user.svg");
 pub const LAYOUT: ImageSource = include_image!("../../assets/ui/layout.svg");
+pub const POI: ImageSource = include_image!("../../assets/ui/poi.svg");
+pub const DRAW: ImageSource = include_image!("../../assets/ui/draw.svg");
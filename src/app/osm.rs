@@ -1,4 +1,22 @@
-use super::osmchange::Tag;
+mod error;
+mod grid;
+mod xml;
+#[cfg(not(target_family = "wasm"))]
+mod auth;
+#[cfg(all(test, not(target_family = "wasm")))]
+pub mod mock;
+
+use super::editor::cache::ElementId;
+use super::osmchange::{DiffResult, OsmChange, Tag};
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+pub use error::{OsmApiError, OsmResult};
+pub use grid::Tile;
+pub use xml::MapProgress;
+
+#[cfg(not(target_family = "wasm"))]
+pub use auth::{PendingAuthorization, AUTHORIZATION_TIMEOUT};
 
 #[cfg(not(target_family = "wasm"))]
 pub use native::OsmClient;
@@ -9,53 +27,72 @@ pub use web::OsmClient;
 const REDIRECT_URI: &str = "urn:ietf:wg:oauth:2.0:oob";
 const SCOPES: &str = "write_api";
 
-type AnyError = Box<dyn std::error::Error + Sync + Send>;
-pub type OsmResult<T> = Result<T, AnyError>;
+const MAX_RETRIES: u32 = 4;
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(2);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(120); // longer rate limits are reported as errors instead
+const MAX_TILE_SPLITS: u32 = 3; // tiles with too many nodes are split into up to 64 requests
+
+// Profile of an openstreetmap-website instance, builtin or added by the user.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct TargetServer {
+	pub name: String,
+	pub host: String, // may include a port, e.g. "localhost:3000"
+	pub client_id: String, // OAuth 2 client ID registered on the server
+	pub https: bool,
+}
 
-#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash)]
-pub enum TargetServer {
-	OpenStreetMap,
-	#[default]
-	OpenStreetMapDev,
+impl Default for TargetServer {
+	fn default() -> Self {
+		Self::openstreetmap_dev()
+	}
 }
 
 impl TargetServer {
-	pub const ITER: [Self; 2] = [Self::OpenStreetMap, Self::OpenStreetMapDev];
-	pub const SIZE: usize = Self::ITER.len();
-
-	pub const fn description(self) -> &'static str {
-		match self {
-			Self::OpenStreetMap => "OpenStreetMap main instance",
-			Self::OpenStreetMapDev => "OpenStreetMap test instance",
+	pub fn openstreetmap() -> Self {
+		Self {
+			name: "OpenStreetMap main instance".into(),
+			host: "www.openstreetmap.org".into(),
+			client_id: String::new(),
+			https: true,
 		}
 	}
 
-	pub const fn base_url(self) -> &'static str {
-		match self {
-			Self::OpenStreetMap => "www.openstreetmap.org",
-			Self::OpenStreetMapDev => "master.apis.dev.openstreetmap.org",
+	pub fn openstreetmap_dev() -> Self {
+		Self {
+			name: "OpenStreetMap test instance".into(),
+			host: "master.apis.dev.openstreetmap.org".into(),
+			client_id: "55c2UqVCKGU_KEhQj4B5wGZHL6fR2dVS5zkwBfkiGd0".into(),
+			https: true,
 		}
 	}
 
-	pub const fn base_token_url(self) -> &'static str {
-		match self {
-			Self::OpenStreetMap => "www.openstreetmap.org/oauth2/token",
-			Self::OpenStreetMapDev => "master.apis.dev.openstreetmap.org/oauth2/token",
-		}
+	pub fn builtin() -> [Self; 2] {
+		[Self::openstreetmap(), Self::openstreetmap_dev()]
 	}
 
-	pub const fn base_auth_url(self) -> &'static str {
-		match self {
-			Self::OpenStreetMap => "www.openstreetmap.org/oauth2/authorize",
-			Self::OpenStreetMapDev => "master.apis.dev.openstreetmap.org/oauth2/authorize",
-		}
+	pub fn is_builtin(&self) -> bool {
+		Self::builtin().contains(self)
 	}
 
-	pub const fn client_id(self) -> &'static str {
-		match self {
-			Self::OpenStreetMap => "",
-			Self::OpenStreetMapDev => "55c2UqVCKGU_KEhQj4B5wGZHL6fR2dVS5zkwBfkiGd0",
-		}
+	pub const fn scheme(&self) -> &'static str {
+		if self.https { "https" } else { "http" }
+	}
+
+	// Url of the website, also used as key for tokens.
+	pub fn base_url(&self) -> String {
+		format!("{}://{}", self.scheme(), self.host)
+	}
+
+	pub fn token_url(&self) -> String {
+		format!("{}/oauth2/token", self.base_url())
+	}
+
+	pub fn auth_url(&self) -> String {
+		format!("{}/oauth2/authorize", self.base_url())
+	}
+
+	pub fn revoke_url(&self) -> String {
+		format!("{}/oauth2/revoke", self.base_url())
 	}
 }
 
@@ -71,10 +108,21 @@ impl Bbox {
 	pub fn area(&self) -> f64 {
 		(self.right - self.left) * (self.top - self.bottom)
 	}
+
+	pub fn quadrants(&self) -> [Self; 4] {
+		let x = f64::midpoint(self.left, self.right);
+		let y = f64::midpoint(self.bottom, self.top);
+		[
+			Self { left: self.left, bottom: self.bottom, right: x, top: y },
+			Self { left: x, bottom: self.bottom, right: self.right, top: y },
+			Self { left: self.left, bottom: y, right: x, top: self.top },
+			Self { left: x, bottom: y, right: self.right, top: self.top },
+		]
+	}
 }
 
 #[allow(dead_code)]
-#[derive(Debug, Clone, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct OsmToken {
 	pub access_token: String,
 	pub token_type: String, // "Bearer"
@@ -82,6 +130,118 @@ pub struct OsmToken {
 	pub created_at: u64,
 }
 
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct UserDetails {
+	pub id: u64,
+	pub display_name: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Account {
+	pub user: UserDetails,
+	pub token: OsmToken,
+}
+
+// Accounts logged in on one server, the active one is used for all authenticated requests.
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Accounts {
+	pub list: Vec<Account>,
+	pub active: Option<u64>, // user id
+}
+
+impl Accounts {
+	pub fn active(&self) -> Option<&Account> {
+		self.active.and_then(|id| self.get(id))
+	}
+
+	pub fn get(&self, user_id: u64) -> Option<&Account> {
+		self.list.iter().find(|x| x.user.id == user_id)
+	}
+
+	// Replaces the account of the same user, for example after logging in again, and activates it.
+	pub fn insert(&mut self, account: Account) {
+		self.active = Some(account.user.id);
+		match self.list.iter_mut().find(|x| x.user.id == account.user.id) {
+			Some(existing) => *existing = account,
+			None => self.list.push(account),
+		}
+	}
+
+	// Another account is activated if the active one was removed.
+	pub fn remove(&mut self, user_id: u64) -> Option<Account> {
+		let i = self.list.iter().position(|x| x.user.id == user_id)?;
+		let account = self.list.remove(i);
+		if self.active == Some(user_id) {
+			self.active = self.list.first().map(|x| x.user.id);
+		}
+		Some(account)
+	}
+
+	pub fn set_active(&mut self, user_id: u64) {
+		if self.get(user_id).is_some() {
+			self.active = Some(user_id);
+		}
+	}
+
+	pub fn update_user(&mut self, user: UserDetails) {
+		if let Some(account) = self.list.iter_mut().find(|x| x.user.id == user.id) {
+			account.user = user;
+		}
+	}
+}
+
+#[derive(serde::Deserialize)]
+struct UserDetailsResponse {
+	user: UserDetails,
+}
+
+// Returned by the upload endpoint when an element was changed on the server in the meantime.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct VersionConflict {
+	pub element: ElementId,
+	pub local_version: u32,
+	pub server_version: u32,
+}
+
+impl VersionConflict {
+	// Parses the body of a 409 response, for example:
+	// "Version mismatch: Provided 2, server had: 3 of Way 1234"
+	fn parse(text: &str) -> Option<Self> {
+		let rest = text.trim().strip_prefix("Version mismatch: Provided ")?;
+		let (local_version, rest) = rest.split_once(", server had: ")?;
+		let (server_version, rest) = rest.split_once(" of ")?;
+		let (kind, id) = rest.split_once(' ')?;
+		let id = id.parse().ok()?;
+
+		let element = match kind {
+			"Node" => ElementId::Node(id),
+			"Way" => ElementId::Way(id),
+			_ => return None,
+		};
+
+		Some(Self {
+			element,
+			local_version: local_version.parse().ok()?,
+			server_version: server_version.parse().ok()?,
+		})
+	}
+}
+
+impl Display for VersionConflict {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		write!(f, "version conflict: {:?} has version {} on the server, but version {} was edited", self.element, self.server_version, self.local_version)
+	}
+}
+
+impl std::error::Error for VersionConflict {}
+
+fn element_path(element: &ElementId) -> String {
+	match element {
+		ElementId::Node(id) => format!("/node/{id}"),
+		ElementId::Way(id) => format!("/way/{id}/full"), // includes the nodes of the way
+	}
+}
+
 #[derive(Debug, serde::Serialize)]
 #[serde(rename = "osm")]
 pub struct OsmCreateChangeset {
@@ -91,96 +251,250 @@ pub struct OsmCreateChangeset {
 #[derive(Debug, serde::Serialize)]
 #[serde(rename = "changeset")]
 pub struct RawChangeset {
+	#[serde(rename = "tag")]
 	tags: Vec<Tag>
 }
 
-fn api_url(path: impl AsRef<str>, target_server: TargetServer) -> String {
+fn api_url(path: impl AsRef<str>, target_server: &TargetServer) -> String {
 	debug_assert!(path.as_ref().starts_with('/'));
-	format!("https://{}/api/0.6{}", target_server.base_url(), path.as_ref())
+	format!("{}/api/0.6{}", target_server.base_url(), path.as_ref())
 }
 
-// used in OsmClient::get_map
-fn api_url_override(path: impl AsRef<str>, server: TargetServer) -> String {
-	debug_assert!(path.as_ref().starts_with('/'));
-	format!("https://{}/api/0.6{}", server.base_url(), path.as_ref())
+// Authorization URL of the out-of-band flow, the server displays the code which is then pasted by the user.
+pub fn client_auth_url(server: &TargetServer) -> String {
+	format!("{}?response_type=code&client_id={}&redirect_uri={REDIRECT_URI}&scope={SCOPES}", server.auth_url(), server.client_id)
+}
+
+fn authorization(token: &OsmToken) -> String {
+	format!("{} {}", token.token_type, token.access_token)
+}
+
+// Percent-encodes everything except unreserved characters, for query parameters and form bodies.
+fn url_encode(value: &str) -> String {
+	let mut result = String::with_capacity(value.len());
+	for byte in value.bytes() {
+		if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+			result.push(byte as char);
+		} else {
+			result.push_str(&format!("%{byte:02X}"));
+		}
+	}
+	result
 }
 
-pub fn client_auth_url(server: TargetServer) -> String {
-	format!("https://{}?response_type=code&client_id={}&redirect_uri={REDIRECT_URI}&scope={SCOPES}", server.base_auth_url(), server.client_id())
+// Authorization code to be exchanged for a token.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct AuthCode {
+	pub code: String,
+	pub redirect_uri: String, // has to match the one of the authorization request
+	pub verifier: Option<String>, // PKCE code verifier
+}
+
+impl AuthCode {
+	pub fn out_of_band(code: &str) -> Self {
+		Self {
+			code: code.trim().to_owned(),
+			redirect_uri: REDIRECT_URI.to_owned(),
+			verifier: None,
+		}
+	}
+
+	fn token_request_body(&self, client_id: &str) -> String {
+		let mut body = format!(
+			"grant_type=authorization_code&code={}&redirect_uri={}&client_id={}",
+			url_encode(&self.code), url_encode(&self.redirect_uri), url_encode(client_id),
+		);
+		if let Some(verifier) = &self.verifier {
+			body.push_str("&code_verifier=");
+			body.push_str(&url_encode(verifier));
+		}
+		body
+	}
 }
 
 #[cfg(not(target_family = "wasm"))]
 mod native {
 	use super::*;
 	use crate::app::osm::TargetServer;
-	use osm_parser::types::raw;
+	use std::collections::HashMap;
+	use super::xml::read_osm_data;
 	use osm_parser::OsmData;
+	use std::io::BufReader;
 	use std::num::NonZeroU32;
-	use std::time::Duration;
+	use ureq::http::Response;
+	use ureq::Body;
 
+	#[derive(Clone)]
 	pub struct OsmClient {
 		pub http_client: ureq::Agent,
 		pub target_server: TargetServer,
-		pub auth_token: [Option<OsmToken>; TargetServer::SIZE],
+		pub accounts: HashMap<String, Accounts>, // by base url of the server
+	}
+
+	// Plain http is only allowed for servers configured without https, e.g. local instances.
+	fn agent(https_only: bool) -> ureq::Agent {
+		ureq::Agent::config_builder()
+			.user_agent(crate::USER_AGENT)
+			.https_only(https_only)
+			.http_status_as_error(false)
+			.max_redirects(0)
+			.timeout_global(Some(Duration::from_secs(30)))
+			.build().into()
+	}
+
+	// Turns error status codes into an OsmApiError, including the error message sent by the API.
+	fn check_status(resp: Response<Body>) -> OsmResult<Response<Body>> {
+		let status = resp.status();
+		if status.is_success() {
+			return Ok(resp);
+		}
+
+		let retry_after = resp.headers().get("retry-after")
+			.and_then(|x| x.to_str().ok())
+			.map(ToOwned::to_owned);
+		let body = resp.into_body().read_to_string().unwrap_or_default();
+
+		Err(OsmApiError::from_status(status.as_u16(), retry_after.as_deref(), body))
+	}
+
+	// The content length of compressed responses does not match the size of the parsed data.
+	fn content_length(resp: &Response<Body>) -> Option<u64> {
+		resp.headers().get("content-encoding").is_none().then(|| resp.body().content_length()).flatten()
+	}
+
+	// Repeats a request on transient errors, on_retry is called with the error and the wait time before each retry.
+	// Only used for requests that are safe to repeat.
+	fn retry<T>(mut on_retry: impl FnMut(&OsmApiError, Duration), mut request: impl FnMut() -> OsmResult<T>) -> OsmResult<T> {
+		let mut attempt = 0;
+		loop {
+			match request() {
+				Err(err) if attempt < MAX_RETRIES && err.is_transient() && err.retry_delay(attempt + 1) <= MAX_RETRY_DELAY => {
+					attempt += 1;
+					let delay = err.retry_delay(attempt);
+					on_retry(&err, delay);
+					std::thread::sleep(delay);
+				}
+				result => return result,
+			}
+		}
 	}
 
 	// todo: auto-add authorization token if available
 	impl OsmClient {
 		pub fn new(target_server: TargetServer) -> Self {
 			Self {
-				http_client: ureq::Agent::config_builder()
-					.user_agent(crate::USER_AGENT)
-					.https_only(true)
-					.max_redirects(0)
-					.timeout_global(Some(Duration::from_secs(30)))
-					.build().into(),
+				http_client: agent(target_server.https),
 				target_server,
-				auth_token: Default::default(),
+				accounts: HashMap::default(),
 			}
 		}
 
-		// todo: error type
-		// todo: move to xml api calls at some point to get rid of json crates
-		pub fn get_map(&self, bbox: &Bbox) -> OsmResult<OsmData> {
-			// always use the main osm instance to fetch map data
-			let url = api_url_override(format!("/map.json?bbox={},{},{},{}", bbox.left, bbox.bottom, bbox.right, bbox.top), TargetServer::OpenStreetMap);
-			let resp = self.http_client.get(url).call()?;
-			let raw = resp.into_body().read_json::<raw::RawOsmData>()?;
-			raw.try_into()
+		pub fn set_target_server(&mut self, target_server: TargetServer) {
+			if target_server.https != self.target_server.https {
+				self.http_client = agent(target_server.https);
+			}
+			self.target_server = target_server;
+		}
+
+		fn authorization(&self) -> OsmResult<String> {
+			let account = self.active_account().ok_or(OsmApiError::NotAuthenticated)?;
+			Ok(authorization(&account.token))
+		}
+
+		pub fn active_account(&self) -> Option<&Account> {
+			self.accounts.get(&self.target_server.base_url()).and_then(Accounts::active)
+		}
+
+		// The response is parsed while it is received, a failed attempt starts over.
+		pub fn get_map(&self, bbox: &Bbox, on_retry: impl FnMut(&OsmApiError, Duration), mut on_progress: impl FnMut(MapProgress)) -> OsmResult<OsmData> {
+			let url = api_url(format!("/map?bbox={},{},{},{}", bbox.left, bbox.bottom, bbox.right, bbox.top), &self.target_server);
+			retry(on_retry, || {
+				let resp = check_status(self.http_client.get(&url).call()?)?;
+				let total_bytes = content_length(&resp);
+				read_osm_data(BufReader::new(resp.into_body().into_reader()), total_bytes, &mut on_progress)
+			})
+		}
+
+		// Tiles in dense areas can contain more nodes than the API returns at once, they are split into quadrants.
+		pub fn get_tile(&self, tile: Tile, mut on_retry: impl FnMut(&OsmApiError, Duration), mut on_progress: impl FnMut(MapProgress)) -> OsmResult<OsmData> {
+			let mut data = OsmData::default();
+			let mut parts = vec![(tile.bbox(), 0)];
+
+			while let Some((bbox, splits)) = parts.pop() {
+				match self.get_map(&bbox, &mut on_retry, &mut on_progress) {
+					Ok(part) => {
+						data.nodes.extend(part.nodes);
+						data.ways.extend(part.ways);
+					}
+					Err(err) if err.is_too_many_nodes() && splits < MAX_TILE_SPLITS => {
+						parts.extend(bbox.quadrants().map(|x| (x, splits + 1)));
+					}
+					Err(err) => return Err(err),
+				}
+			}
+
+			Ok(data)
 		}
 
-		// todo: error type
 		pub fn create_changeset(&self, tags: Vec<Tag>) -> OsmResult<NonZeroU32> {
-			let url = api_url("/changeset/create", self.target_server);
-			let auth = self.auth_token.get(self.target_server as usize).unwrap().as_ref().ok_or("missing auth token")?;
+			let url = api_url("/changeset/create", &self.target_server);
 			let data = OsmCreateChangeset { changeset: RawChangeset { tags } };
 			let body = quick_xml::se::to_string(&data)?;
-			let resp = self.http_client.put(url)
-				.header("authorization", format!("{} {}", auth.token_type, auth.access_token))
-				.send(body)?;
-			resp.into_body()
-				.read_to_string()?
-				.parse().map_err(Box::from)
+			let resp = check_status(self.http_client.put(url)
+				.header("authorization", self.authorization()?)
+				.send(body)?)?;
+			Ok(resp.into_body().read_to_string()?.trim().parse()?)
+		}
+
+		pub fn upload_changeset(&self, id: NonZeroU32, osmchange: &OsmChange) -> OsmResult<DiffResult> {
+			let url = api_url(format!("/changeset/{id}/upload"), &self.target_server);
+			let body = osmchange.to_string_pretty()?;
+			let resp = check_status(self.http_client.post(url)
+				.header("authorization", self.authorization()?)
+				.header("content-type", "text/xml")
+				.send(body)?)?;
+			let text = resp.into_body().read_to_string()?;
+			Ok(DiffResult::parse(&text)?)
+		}
+
+		// Fetches the current version of an element from the target server.
+		pub fn get_element(&self, element: &ElementId) -> OsmResult<OsmData> {
+			let url = api_url(element_path(element), &self.target_server);
+			retry(|_, _| {}, || {
+				let resp = check_status(self.http_client.get(&url).call()?)?;
+				read_osm_data(BufReader::new(resp.into_body().into_reader()), None, |_| {})
+			})
 		}
 
-		// todo: error type
 		pub fn close_changeset(&self, id: NonZeroU32) -> OsmResult<NonZeroU32> {
-			let url = api_url(format!("/changeset/{id}/close"), self.target_server);
-			let auth = self.auth_token.get(self.target_server as usize).unwrap().as_ref().ok_or("missing auth token")?;
-			self.http_client.put(url)
-				.header("authorization", format!("{} {}", auth.token_type, auth.access_token))
-				.send_empty()
-				.map(|_| id)
-				.map_err(Box::from)
+			let url = api_url(format!("/changeset/{id}/close"), &self.target_server);
+			check_status(self.http_client.put(url)
+				.header("authorization", self.authorization()?)
+				.send_empty()?)?;
+			Ok(id)
+		}
+
+		pub fn fetch_token(&self, auth_code: &AuthCode) -> OsmResult<OsmToken> {
+			let url = self.target_server.token_url();
+			let body = auth_code.token_request_body(&self.target_server.client_id);
+			let resp = check_status(self.http_client.post(url).header("content-type", "application/x-www-form-urlencoded").send(body)?)?;
+			Ok(resp.into_body().read_json::<OsmToken>()?)
+		}
+
+		// Also used to check whether the token is still valid.
+		pub fn get_user_details(&self, token: &OsmToken) -> OsmResult<UserDetails> {
+			let url = api_url("/user/details.json", &self.target_server);
+			let resp = check_status(self.http_client.get(url)
+				.header("authorization", authorization(token))
+				.call()?)?;
+			Ok(resp.into_body().read_json::<UserDetailsResponse>()?.user)
 		}
 
-		// todo: error type
-		pub fn fetch_token(&self, auth_code: impl AsRef<str>) -> OsmResult<OsmToken> {
-			let url = format!("https://{}", self.target_server.base_token_url());
-			let body = format!("grant_type=authorization_code&code={}&redirect_uri={REDIRECT_URI}&client_id={}", auth_code.as_ref(), self.target_server.client_id());
-			let resp = self.http_client.post(url).header("content-type", "application/x-www-form-urlencoded").send(body)?;
-			resp.into_body().read_json::<OsmToken>()
-				.map_err(Box::from)
+		pub fn revoke_token(&self, token: &OsmToken) -> OsmResult<()> {
+			let url = self.target_server.revoke_url();
+			let body = format!("token={}&client_id={}", url_encode(&token.access_token), url_encode(&self.target_server.client_id));
+			check_status(self.http_client.post(url).header("content-type", "application/x-www-form-urlencoded").send(body)?)?;
+			Ok(())
 		}
 	}
 }
@@ -189,14 +503,66 @@ mod native {
 mod web {
 	use super::*;
 	use crate::app::osm::TargetServer;
-	use ehttp::Request;
-	use osm_parser::types::raw;
+	use std::collections::HashMap;
+	use ehttp::{Request, Response};
+	use super::xml::read_osm_data;
 	use osm_parser::OsmData;
 	use std::num::NonZeroU32;
 
+	#[derive(Clone)]
 	pub struct OsmClient {
 		pub target_server: TargetServer,
-		pub auth_token: [Option<OsmToken>; TargetServer::SIZE],
+		pub accounts: HashMap<String, Accounts>, // by base url of the server
+	}
+
+	// Sends the request and turns error status codes into an OsmApiError, including the error message sent by the API.
+	async fn fetch(request: Request) -> OsmResult<Response> {
+		let resp = ehttp::fetch_async(request).await
+			.map_err(OsmApiError::Network)?;
+
+		if resp.ok {
+			Ok(resp)
+		} else {
+			let body = resp.text().unwrap_or_default().to_owned();
+			Err(OsmApiError::from_status(resp.status, resp.headers.get("retry-after"), body))
+		}
+	}
+
+	// Resolves after the given duration, using setTimeout of the global scope.
+	async fn sleep(duration: Duration) {
+		use wasm_bindgen::{JsCast, JsValue};
+
+		#[allow(clippy::cast_possible_truncation)]
+		let millis = duration.as_millis() as f64;
+		let promise = js_sys::Promise::new(&mut |resolve, reject| {
+			let result = js_sys::Reflect::get(&js_sys::global(), &"setTimeout".into())
+				.and_then(|f| f.dyn_into::<js_sys::Function>())
+				.and_then(|f| f.call2(&JsValue::NULL, &resolve, &millis.into()));
+
+			if let Err(err) = result {
+				let _ = reject.call1(&JsValue::NULL, &err);
+			}
+		});
+
+		let _ = wasm_bindgen_futures::JsFuture::from(promise).await;
+	}
+
+	// Repeats a request on transient errors, on_retry is called with the error and the wait time before each retry.
+	// Only used for requests that are safe to repeat.
+	#[allow(clippy::future_not_send)]
+	async fn retry<T, F: Future<Output = OsmResult<T>>>(mut on_retry: impl FnMut(&OsmApiError, Duration), mut request: impl FnMut() -> F) -> OsmResult<T> {
+		let mut attempt = 0;
+		loop {
+			match request().await {
+				Err(err) if attempt < MAX_RETRIES && err.is_transient() && err.retry_delay(attempt + 1) <= MAX_RETRY_DELAY => {
+					attempt += 1;
+					let delay = err.retry_delay(attempt);
+					on_retry(&err, delay);
+					sleep(delay).await;
+				}
+				result => return result,
+			}
+		}
 	}
 
 	#[allow(clippy::future_not_send)]
@@ -204,65 +570,364 @@ mod web {
 		pub fn new(target_server: TargetServer) -> Self {
 			Self {
 				target_server,
-				auth_token: Default::default(),
+				accounts: HashMap::default(),
 			}
 		}
 
-		pub async fn get_map(&self, bbox: &Bbox) -> OsmResult<OsmData> {
-			let url = api_url_override(format!("/map.json?bbox={},{},{},{}", bbox.left, bbox.bottom, bbox.right, bbox.top), TargetServer::OpenStreetMap);
-			let resp = ehttp::fetch_async(Request::get(url)).await
-				.map(|x| if x.ok { Ok(x) } else { Err(format!("request failed with status code {}", x.status)) })??;
+		pub fn set_target_server(&mut self, target_server: TargetServer) {
+			self.target_server = target_server;
+		}
+
+		fn authorization(&self) -> OsmResult<String> {
+			let account = self.active_account().ok_or(OsmApiError::NotAuthenticated)?;
+			Ok(authorization(&account.token))
+		}
+
+		pub fn active_account(&self) -> Option<&Account> {
+			self.accounts.get(&self.target_server.base_url()).and_then(Accounts::active)
+		}
 
-			let raw = resp.json::<raw::RawOsmData>()?;
-			raw.try_into()
+		// The browser provides the response at once, so progress is only reported while parsing.
+		pub async fn get_map(&self, bbox: &Bbox, on_retry: impl FnMut(&OsmApiError, Duration), on_progress: impl FnMut(MapProgress)) -> OsmResult<OsmData> {
+			let url = api_url(format!("/map?bbox={},{},{},{}", bbox.left, bbox.bottom, bbox.right, bbox.top), &self.target_server);
+			let resp = retry(on_retry, || fetch(Request::get(&url))).await?;
+			read_osm_data(resp.bytes.as_slice(), Some(resp.bytes.len() as u64), on_progress)
+		}
+
+		pub async fn get_tile(&self, tile: Tile, mut on_retry: impl FnMut(&OsmApiError, Duration), mut on_progress: impl FnMut(MapProgress)) -> OsmResult<OsmData> {
+			let mut data = OsmData::default();
+			let mut parts = vec![(tile.bbox(), 0)];
+
+			while let Some((bbox, splits)) = parts.pop() {
+				match self.get_map(&bbox, &mut on_retry, &mut on_progress).await {
+					Ok(part) => {
+						data.nodes.extend(part.nodes);
+						data.ways.extend(part.ways);
+					}
+					Err(err) if err.is_too_many_nodes() && splits < MAX_TILE_SPLITS => {
+						parts.extend(bbox.quadrants().map(|x| (x, splits + 1)));
+					}
+					Err(err) => return Err(err),
+				}
+			}
+
+			Ok(data)
 		}
 
 		pub async fn create_changeset(&self, tags: Vec<Tag>) -> OsmResult<NonZeroU32> {
-			let url = api_url("/changeset/create", self.target_server);
-			let auth = self.auth_token.get(self.target_server as usize).unwrap().as_ref().ok_or("missing auth token")?;
+			let url = api_url("/changeset/create", &self.target_server);
 			let data = OsmCreateChangeset { changeset: RawChangeset { tags } };
 			let body = quick_xml::se::to_string(&data)?;
-			let resp = ehttp::fetch_async(Request {
+			let resp = fetch(Request {
 				method: "PUT".into(),
 				url,
 				body: body.into_bytes(),
-				headers: ehttp::Headers::new(&[("authorization", &format!("{} {}", auth.token_type, auth.access_token))]),
+				headers: ehttp::Headers::new(&[("authorization", &self.authorization()?)]),
+				mode: ehttp::Mode::default(),
+			}).await?;
+
+			Ok(String::from_utf8(resp.bytes)?.trim().parse()?)
+		}
+
+		pub async fn upload_changeset(&self, id: NonZeroU32, osmchange: &OsmChange) -> OsmResult<DiffResult> {
+			let url = api_url(format!("/changeset/{id}/upload"), &self.target_server);
+			let body = osmchange.to_string_pretty()?;
+			let resp = fetch(Request {
+				method: "POST".into(),
+				url,
+				body: body.into_bytes(),
+				headers: ehttp::Headers::new(&[
+					("authorization", &self.authorization()?),
+					("content-type", "text/xml"),
+				]),
 				mode: ehttp::Mode::default(),
-			}).await
-				.map(|x| if x.ok { Ok(x) } else { Err(format!("request failed with status code {}", x.status)) })??;
+			}).await?;
+
+			let text = String::from_utf8(resp.bytes)?;
+			Ok(DiffResult::parse(&text)?)
+		}
 
-			String::from_utf8(resp.bytes)?
-				.parse().map_err(Box::from)
+		pub async fn get_element(&self, element: &ElementId) -> OsmResult<OsmData> {
+			let url = api_url(element_path(element), &self.target_server);
+			let resp = retry(|_, _| {}, || fetch(Request::get(&url))).await?;
+			read_osm_data(resp.bytes.as_slice(), None, |_| {})
 		}
 
 		pub async fn close_changeset(&self, id: NonZeroU32) -> OsmResult<NonZeroU32> {
-			let url = api_url(format!("/changeset/{id}/close"), self.target_server);
-			let auth = self.auth_token.get(self.target_server as usize).unwrap().as_ref().ok_or("missing auth token")?;
-			ehttp::fetch_async(Request {
+			let url = api_url(format!("/changeset/{id}/close"), &self.target_server);
+			fetch(Request {
 				method: "PUT".into(),
 				url,
 				body: vec![],
-				headers: ehttp::Headers::new(&[("authorization", &format!("{} {}", auth.token_type, auth.access_token))]),
+				headers: ehttp::Headers::new(&[("authorization", &self.authorization()?)]),
+				mode: ehttp::Mode::default(),
+			}).await?;
+
+			Ok(id)
+		}
+
+		pub async fn fetch_token(&self, auth_code: &AuthCode) -> OsmResult<OsmToken> {
+			let url = self.target_server.token_url();
+			let body = auth_code.token_request_body(&self.target_server.client_id);
+			let resp = fetch(Request {
+				method: "POST".into(),
+				url,
+				body: body.into_bytes(),
+				headers: ehttp::Headers::new(&[("content-type", "application/x-www-form-urlencoded")]),
+				mode: ehttp::Mode::default(),
+			}).await?;
+
+			resp.json::<OsmToken>().map_err(OsmApiError::parse)
+		}
+
+		pub async fn get_user_details(&self, token: &OsmToken) -> OsmResult<UserDetails> {
+			let url = api_url("/user/details.json", &self.target_server);
+			let resp = fetch(Request {
+				method: "GET".into(),
+				url,
+				body: vec![],
+				headers: ehttp::Headers::new(&[("authorization", &authorization(token))]),
 				mode: ehttp::Mode::default(),
-			}).await
-				.map(|x| if x.ok { Ok(id) } else { Err(format!("request failed with status code {}", x.status)) })?
-				.map_err(Box::from)
+			}).await?;
+
+			resp.json::<UserDetailsResponse>().map(|x| x.user).map_err(OsmApiError::parse)
 		}
 
-		pub async fn fetch_token(&self, auth_code: impl AsRef<str>) -> OsmResult<OsmToken> {
-			let url = format!("https://{}", self.target_server.base_token_url());
-			let body = format!("grant_type=authorization_code&code={}&redirect_uri={REDIRECT_URI}&client_id={}", auth_code.as_ref(), self.target_server.client_id());
-			let resp = ehttp::fetch_async(Request {
+		pub async fn revoke_token(&self, token: &OsmToken) -> OsmResult<()> {
+			let url = self.target_server.revoke_url();
+			let body = format!("token={}&client_id={}", url_encode(&token.access_token), url_encode(&self.target_server.client_id));
+			fetch(Request {
 				method: "POST".into(),
 				url,
 				body: body.into_bytes(),
 				headers: ehttp::Headers::new(&[("content-type", "application/x-www-form-urlencoded")]),
 				mode: ehttp::Mode::default(),
-			}).await
-				.map(|x| if x.ok { Ok(x) } else { Err(format!("request failed with status code {}", x.status)) })??;
+			}).await?;
+
+			Ok(())
+		}
+	}
+}
 
-			resp.json::<OsmToken>()
-				.map_err(Box::from)
+#[cfg(all(test, not(target_family = "wasm")))]
+mod tests {
+	use super::*;
+	use super::mock::{self, MockOsmApi, MockResponse, ACCESS_TOKEN};
+	use crate::app::editor::cache::{Change, Element};
+	use crate::app::osmchange::DiffEntry;
+	use osm_parser::{Coordinate, Tags};
+	use std::num::NonZeroU32;
+
+	fn node(id: osm_parser::Id, version: u32) -> osm_parser::Node {
+		osm_parser::Node { id, pos: Coordinate::new(50.0, 10.0), tags: Tags::default(), version, changeset: 1 }
+	}
+
+	fn way(id: osm_parser::Id, version: u32, nodes: Vec<osm_parser::Id>) -> osm_parser::Way {
+		let mut tags = Tags::default();
+		tags.insert("highway".into(), "footway".into());
+		osm_parser::Way { id, nodes, tags, version, changeset: 1 }
+	}
+
+	fn bbox() -> Bbox {
+		Bbox { left: 9.9, bottom: 49.9, right: 10.1, top: 50.1 }
+	}
+
+	fn mock_with_way() -> MockOsmApi {
+		let mock = MockOsmApi::start();
+		mock.add_element(Element::Node(node(1, 1)));
+		mock.add_element(Element::Node(node(2, 1)));
+		mock.add_element(Element::Way(way(10, 1, vec![1, 2])));
+		mock
+	}
+
+	#[test]
+	fn get_map() {
+		let mock = mock_with_way();
+		let mut progress = Vec::new();
+		let data = mock.client().get_map(&bbox(), |err, _| panic!("unexpected retry: {err}"), |x| progress.push(x)).unwrap();
+
+		assert_eq!(data.nodes.len(), 2);
+		assert!(matches!(progress.as_slice(), [MapProgress { elements: 3, bytes, total_bytes: Some(total) }] if bytes == total));
+		assert_eq!(data.ways.get(&10).unwrap().nodes, vec![1, 2]);
+
+		let requests = mock.requests();
+		assert_eq!(requests.len(), 1);
+		assert_eq!(requests[0].method, "GET");
+		assert!(requests[0].path.starts_with("/api/0.6/map?bbox=9.9,49.9,10.1,50.1"));
+	}
+
+	#[test]
+	fn get_element() {
+		let mock = mock_with_way();
+		let data = mock.client().get_element(&ElementId::Way(10)).unwrap();
+
+		assert_eq!(data.nodes.len(), 2);
+		assert_eq!(data.ways.len(), 1);
+		assert_eq!(mock.requests()[0].path, "/api/0.6/way/10/full");
+	}
+
+	#[test]
+	fn upload_changeset() {
+		let mock = mock_with_way();
+		let client = mock.client();
+
+		let created = node(osm_parser::Id::MAX, 0);
+		let changes = vec![
+			Change::CreateNode(created),
+			Change::UpdateWay(way(10, 1, vec![1, 2]), way(10, 1, vec![1, 2, osm_parser::Id::MAX])),
+		];
+
+		let id = client.create_changeset(vec![Tag { k: "comment".into(), v: "test".into() }]).unwrap();
+		let mut osmchange = OsmChange::from(&changes);
+		osmchange.prepare_upload(id.get().into());
+		let diff = client.upload_changeset(id, &osmchange).unwrap();
+		assert_eq!(client.close_changeset(id).unwrap(), id);
+
+		let requests = mock.requests();
+		assert_eq!(requests.iter().map(|x| (x.method.as_str(), x.path.as_str())).collect::<Vec<_>>(), [
+			("PUT", "/api/0.6/changeset/create"),
+			("POST", "/api/0.6/changeset/1/upload"),
+			("PUT", "/api/0.6/changeset/1/close"),
+		]);
+		let authorization = format!("Bearer {ACCESS_TOKEN}");
+		assert!(requests.iter().all(|x| x.header("authorization") == Some(authorization.as_str())));
+		assert!(requests[0].body.contains(r#"<tag k="comment" v="test"/>"#));
+		assert_eq!(requests[1].header("content-type"), Some("text/xml"));
+		assert_eq!(requests[1].body, osmchange.to_string_pretty().unwrap());
+
+		let [DiffEntry::Node(created), DiffEntry::Way(modified)] = diff.entries.as_slice() else {
+			panic!("unexpected diff result: {diff:?}");
+		};
+		assert_eq!((created.old_id, created.new_id, created.new_version), (-1, Some(1_000_000), Some(1)));
+		assert_eq!((modified.old_id, modified.new_id, modified.new_version), (10, Some(10), Some(2)));
+
+		let Some(Element::Way(server_way)) = mock.latest(&ElementId::Way(10)) else { panic!("way missing"); };
+		assert_eq!(server_way.nodes, vec![1, 2, 1_000_000]);
+	}
+
+	#[test]
+	fn version_conflict() {
+		let mock = mock_with_way();
+		mock.add_element(Element::Way(way(10, 2, vec![2, 1]))); // changed by someone else
+		let client = mock.client();
+
+		let mut osmchange = OsmChange::from(&vec![Change::UpdateWay(way(10, 1, vec![1, 2]), way(10, 1, vec![1]))]);
+		osmchange.prepare_upload(1);
+
+		match client.upload_changeset(NonZeroU32::MIN, &osmchange) {
+			Err(OsmApiError::Conflict(conflict)) => {
+				assert_eq!(conflict.element, ElementId::Way(10));
+				assert_eq!((conflict.local_version, conflict.server_version), (1, 2));
+			}
+			result => panic!("expected a conflict, got {result:?}"),
 		}
 	}
+
+	#[test]
+	fn split_tile_with_too_many_nodes() {
+		let mock = mock_with_way();
+		let tile = Tile::covering(&bbox())[0];
+		mock.queue_response("GET", "/api/0.6/map", MockResponse::new(400, "You requested too many nodes (limit is 50000). Either request a smaller area, or use planet.osm"));
+
+		let data = mock.client().get_tile(tile, |err, _| panic!("unexpected retry: {err}"), |_| {}).unwrap();
+		assert_eq!(data.ways.get(&10).unwrap().nodes, vec![1, 2]);
+
+		let requests = mock.requests();
+		assert_eq!(requests.len(), 1 + 4);
+		let quadrant = tile.bbox().quadrants()[3];
+		assert!(requests[1].path.ends_with(&format!("bbox={},{},{},{}", quadrant.left, quadrant.bottom, quadrant.right, quadrant.top)));
+	}
+
+	#[test]
+	fn retry_after_rate_limit() {
+		let mock = mock_with_way();
+		mock.queue_response("GET", "/api/0.6/map", MockResponse::new(429, "").header("retry-after", "0"));
+
+		let mut retries = Vec::new();
+		let data = mock.client().get_map(&bbox(), |err, delay| retries.push((err.clone(), delay)), |_| {});
+
+		assert!(data.is_ok());
+		assert_eq!(mock.requests().len(), 2);
+		assert!(matches!(retries.as_slice(), [(OsmApiError::RateLimited { status: 429, .. }, delay)] if delay.is_zero()));
+	}
+
+	#[test]
+	fn rate_limit_exceeding_max_delay_is_not_retried() {
+		let mock = mock_with_way();
+		mock.queue_response("GET", "/api/0.6/map", MockResponse::new(509, "").header("retry-after", "3600"));
+
+		let result = mock.client().get_map(&bbox(), |err, _| panic!("unexpected retry: {err}"), |_| {});
+		assert!(matches!(result, Err(OsmApiError::RateLimited { status: 509, retry_after: Some(_) })));
+	}
+
+	#[test]
+	fn authentication() {
+		let mock = MockOsmApi::start();
+
+		let client = OsmClient::new(mock.target_server());
+		assert!(matches!(client.create_changeset(Vec::new()), Err(OsmApiError::NotAuthenticated)));
+		assert!(mock.requests().is_empty());
+
+		let mut client = mock.client();
+		client.accounts.get_mut(&mock.target_server().base_url()).unwrap().list[0].token.access_token = "revoked".into();
+		assert!(matches!(client.create_changeset(Vec::new()), Err(OsmApiError::AuthExpired)));
+	}
+
+	#[test]
+	fn user_details_and_revoke() {
+		let mock = MockOsmApi::start();
+		let client = mock.client();
+
+		let user = client.get_user_details(&mock::token()).unwrap();
+		assert_eq!(user.display_name, mock::USER_NAME);
+
+		client.revoke_token(&mock::token()).unwrap();
+		let request = mock.requests().pop().unwrap();
+		assert_eq!(request.path, "/oauth2/revoke");
+		assert!(request.body.contains(&format!("token={ACCESS_TOKEN}")));
+
+		// the token is no longer accepted
+		assert!(matches!(client.get_user_details(&mock::token()), Err(OsmApiError::AuthExpired)));
+	}
+
+	#[test]
+	fn accounts() {
+		let account = |id, name: &str| Account {
+			user: UserDetails { id, display_name: name.into() },
+			token: mock::token(),
+		};
+
+		let mut accounts = Accounts::default();
+		assert!(accounts.active().is_none());
+
+		accounts.insert(account(1, "mapper"));
+		accounts.insert(account(2, "mapper_import"));
+		assert_eq!(accounts.active().unwrap().user.display_name, "mapper_import");
+
+		// logging in again replaces the account
+		accounts.insert(account(1, "renamed"));
+		assert_eq!(accounts.list.len(), 2);
+		assert_eq!(accounts.active().unwrap().user.display_name, "renamed");
+
+		accounts.set_active(3);
+		assert_eq!(accounts.active, Some(1));
+
+		assert!(accounts.remove(1).is_some());
+		assert_eq!(accounts.active().unwrap().user.id, 2);
+		assert!(accounts.remove(2).is_some());
+		assert!(accounts.active().is_none());
+	}
+
+	#[test]
+	fn fetch_token() {
+		let mock = MockOsmApi::start();
+		let token = OsmClient::new(mock.target_server()).fetch_token(&AuthCode::out_of_band("auth-code")).unwrap();
+
+		assert_eq!(token.access_token, ACCESS_TOKEN);
+		let request = &mock.requests()[0];
+		assert_eq!(request.path, "/oauth2/token");
+		assert!(request.body.contains("code=auth-code"));
+		assert!(request.body.contains("redirect_uri=urn%3Aietf%3Awg%3Aoauth%3A2.0%3Aoob"));
+		assert!(request.body.contains("client_id=mock-client-id"));
+	}
 }
@@ -0,0 +1,243 @@
+// OAuth 2 authorization code flow with PKCE (RFC 7636), the browser is redirected to a loopback listener (RFC 8252).
+// The client application on the server needs "http://127.0.0.1/callback" as redirect URI, the port may differ.
+
+use super::{url_encode, AuthCode, OsmApiError, OsmResult, TargetServer, SCOPES};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+const CALLBACK_PATH: &str = "/callback";
+pub const AUTHORIZATION_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+pub struct Pkce {
+	pub verifier: String,
+	pub challenge: String,
+}
+
+impl Pkce {
+	#[allow(clippy::new_without_default)]
+	pub fn new() -> Self {
+		Self::from_verifier(random_string())
+	}
+
+	// Uses the S256 method, the challenge is the url-safe base64 encoded SHA-256 hash of the verifier.
+	pub fn from_verifier(verifier: String) -> Self {
+		let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+		Self { verifier, challenge }
+	}
+}
+
+// 32 random bytes as 43 url-safe characters, used for the code verifier and the state parameter.
+pub fn random_string() -> String {
+	let mut bytes = [0; 32];
+	getrandom::fill(&mut bytes).expect("failed to get random bytes");
+	URL_SAFE_NO_PAD.encode(bytes)
+}
+
+// An authorization in progress, waiting for the browser to be redirected back after the user granted access.
+pub struct PendingAuthorization {
+	pub url: String, // to be opened in the browser
+	listener: TcpListener,
+	redirect_uri: String,
+	pkce: Pkce,
+	state: String,
+}
+
+impl PendingAuthorization {
+	pub fn start(server: &TargetServer) -> OsmResult<Self> {
+		let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+			.and_then(|x| x.set_nonblocking(true).map(|()| x))
+			.map_err(|err| OsmApiError::Network(err.to_string()))?;
+		let port = listener.local_addr().map_err(|err| OsmApiError::Network(err.to_string()))?.port();
+
+		let redirect_uri = format!("http://127.0.0.1:{port}{CALLBACK_PATH}");
+		let pkce = Pkce::new();
+		let state = random_string();
+		let url = format!(
+			"{}?response_type=code&client_id={}&redirect_uri={}&scope={}&state={state}&code_challenge={}&code_challenge_method=S256",
+			server.auth_url(), url_encode(&server.client_id), url_encode(&redirect_uri), url_encode(SCOPES), pkce.challenge,
+		);
+
+		Ok(Self { url, listener, redirect_uri, pkce, state })
+	}
+
+	// Blocks until the browser was redirected to the listener or the timeout has passed.
+	pub fn wait(self, timeout: Duration) -> OsmResult<AuthCode> {
+		let deadline = Instant::now() + timeout;
+
+		loop {
+			match self.listener.accept() {
+				Ok((stream, _)) => {
+					if let Some(result) = self.handle_redirect(stream) {
+						return result.map(|code| AuthCode {
+							code,
+							redirect_uri: self.redirect_uri,
+							verifier: Some(self.pkce.verifier),
+						});
+					}
+				}
+				Err(err) if err.kind() == ErrorKind::WouldBlock => {
+					if Instant::now() >= deadline {
+						return Err(OsmApiError::Authorization("timed out waiting for the browser".into()));
+					}
+					std::thread::sleep(Duration::from_millis(100));
+				}
+				Err(err) => return Err(OsmApiError::Network(err.to_string())),
+			}
+		}
+	}
+
+	// Returns None for unrelated requests, like the favicon.
+	fn handle_redirect(&self, mut stream: TcpStream) -> Option<OsmResult<String>> {
+		let _ = stream.set_nonblocking(false);
+		let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+
+		let mut line = String::new();
+		BufReader::new(&stream).read_line(&mut line).ok()?;
+		let target = line.split_whitespace().nth(1)?;
+
+		let Some(query) = target.strip_prefix(CALLBACK_PATH).and_then(|x| x.strip_prefix('?')) else {
+			let _ = respond(&mut stream, "404 Not Found", "");
+			return None;
+		};
+
+		let params = query_params(query);
+		let param = |name: &str| params.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str());
+
+		// ignore redirects that do not belong to this authorization
+		if param("state") != Some(self.state.as_str()) {
+			let _ = respond(&mut stream, "400 Bad Request", "Invalid state, please try again.");
+			return None;
+		}
+
+		let result = match (param("code"), param("error")) {
+			(Some(code), _) => Ok(code.to_owned()),
+			(None, error) => Err(OsmApiError::Authorization(param("error_description").or(error).unwrap_or("no code received").to_owned())),
+		};
+
+		let message = match &result {
+			Ok(_) => format!("Logged in to {}. You can close this tab now.", env!("CARGO_PKG_NAME")),
+			Err(err) => err.to_string(),
+		};
+		let _ = respond(&mut stream, "200 OK", &message);
+
+		Some(result)
+	}
+}
+
+fn respond(stream: &mut TcpStream, status: &str, message: &str) -> std::io::Result<()> {
+	let body = format!("<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{}</title></head><body><p>{message}</p></body></html>", env!("CARGO_PKG_NAME"));
+	write!(stream, "HTTP/1.1 {status}\r\ncontent-type: text/html; charset=utf-8\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}", body.len())?;
+	stream.flush()
+}
+
+// Parses "a=1&b=x%20y" into its decoded key value pairs.
+pub fn query_params(query: &str) -> Vec<(String, String)> {
+	query.split('&')
+		.filter(|x| !x.is_empty())
+		.map(|x| {
+			let (k, v) = x.split_once('=').unwrap_or((x, ""));
+			(percent_decode(k), percent_decode(v))
+		})
+		.collect()
+}
+
+fn percent_decode(value: &str) -> String {
+	let bytes = value.as_bytes();
+	let mut result = Vec::with_capacity(bytes.len());
+
+	let mut i = 0;
+	while i < bytes.len() {
+		if bytes[i] == b'%' && let Some(byte) = value.get(i + 1..i + 3).and_then(|x| u8::from_str_radix(x, 16).ok()) {
+			result.push(byte);
+			i += 3;
+		} else {
+			result.push(if bytes[i] == b'+' { b' ' } else { bytes[i] });
+			i += 1;
+		}
+	}
+
+	String::from_utf8_lossy(&result).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::app::osm::mock::{self, MockOsmApi};
+	use crate::app::osm::OsmClient;
+
+	#[test]
+	fn pkce_rfc_7636_example() {
+		// appendix B of RFC 7636
+		let pkce = Pkce::from_verifier("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk".into());
+		assert_eq!(pkce.challenge, "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM");
+	}
+
+	#[test]
+	fn pkce_verifier() {
+		let a = Pkce::new();
+		let b = Pkce::new();
+
+		// 43 to 128 unreserved characters
+		assert_eq!(a.verifier.len(), 43);
+		assert!(a.verifier.chars().all(|c| c.is_ascii_alphanumeric() || "-._~".contains(c)));
+		assert_ne!(a.verifier, b.verifier);
+		assert_eq!(Pkce::from_verifier(a.verifier.clone()).challenge, a.challenge);
+	}
+
+	#[test]
+	fn query() {
+		assert_eq!(query_params("code=a%2Fb+c&state=&error"), [
+			("code".to_owned(), "a/b c".to_owned()),
+			("state".to_owned(), String::new()),
+			("error".to_owned(), String::new()),
+		]);
+		assert_eq!(percent_decode("%E2%9C%94%zz"), "✔%zz");
+	}
+
+	#[test]
+	fn loopback_redirect() {
+		let mock = MockOsmApi::start();
+		let server = mock.target_server();
+		let pending = PendingAuthorization::start(&server).unwrap();
+		let url = pending.url.clone();
+		assert!(url.contains("code_challenge_method=S256"));
+		assert!(url.contains(&format!("code_challenge={}", pending.pkce.challenge)));
+
+		let waiting = std::thread::spawn(move || pending.wait(Duration::from_secs(10)));
+
+		// the browser follows the redirect of the fake authorization server to the listener
+		let page = ureq::get(&url).call().unwrap().into_body().read_to_string().unwrap();
+		assert!(page.contains("You can close this tab now."));
+
+		let auth_code = waiting.join().unwrap().unwrap();
+		assert_eq!(auth_code.code, mock::AUTH_CODE);
+		assert!(auth_code.redirect_uri.starts_with("http://127.0.0.1:"));
+
+		// the server checks the verifier against the challenge of the authorization request
+		let token = OsmClient::new(server).fetch_token(&auth_code).unwrap();
+		assert_eq!(token.access_token, mock::ACCESS_TOKEN);
+
+		let request = mock.requests().into_iter().find(|x| x.path == "/oauth2/token").unwrap();
+		assert!(request.body.contains(&format!("code_verifier={}", auth_code.verifier.unwrap())));
+	}
+
+	#[test]
+	fn loopback_denied() {
+		let pending = PendingAuthorization::start(&MockOsmApi::start().target_server()).unwrap();
+		let redirect = format!("{}?error=access_denied&state={}", pending.redirect_uri, pending.state);
+		let waiting = std::thread::spawn(move || pending.wait(Duration::from_secs(10)));
+
+		ureq::get(redirect).call().unwrap();
+		assert!(matches!(waiting.join().unwrap(), Err(OsmApiError::Authorization(err)) if err == "access_denied"));
+	}
+
+	#[test]
+	fn loopback_timeout() {
+		let pending = PendingAuthorization::start(&MockOsmApi::start().target_server()).unwrap();
+		assert!(matches!(pending.wait(Duration::ZERO), Err(OsmApiError::Authorization(_))));
+	}
+}
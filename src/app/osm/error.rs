@@ -0,0 +1,144 @@
+use super::{VersionConflict, MAX_RETRY_DELAY, RETRY_BASE_DELAY};
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+pub type OsmResult<T> = Result<T, OsmApiError>;
+
+// Errors of the OSM API, shared by the native and web clients.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub enum OsmApiError {
+	/// The server responded with an unexpected status code, body contains the error message of the API.
+	Status { status: u16, body: String },
+	/// The uploaded element was changed on the server in the meantime.
+	Conflict(VersionConflict),
+	/// No token is available for the target server.
+	NotAuthenticated,
+	/// The token was rejected, it has expired or was revoked.
+	AuthExpired,
+	/// The user denied access or the authorization flow could not be completed.
+	Authorization(String),
+	/// Too many requests (429) or bandwidth limit exceeded (509).
+	RateLimited { status: u16, retry_after: Option<Duration> },
+	/// The server could not be reached.
+	Network(String),
+	/// The request or response could not be (de)serialized.
+	Parse(String),
+	/// The request was cancelled by the user.
+	Cancelled,
+}
+
+impl OsmApiError {
+	pub fn from_status(status: u16, retry_after: Option<&str>, body: String) -> Self {
+		match status {
+			401 => Self::AuthExpired,
+			409 => match VersionConflict::parse(&body) {
+				Some(conflict) => Self::Conflict(conflict),
+				None => Self::Status { status, body },
+			},
+			429 | 509 => Self::RateLimited {
+				status,
+				retry_after: retry_after
+					.and_then(|x| x.trim().parse().ok())
+					.map(Duration::from_secs),
+			},
+			_ => Self::Status { status, body },
+		}
+	}
+
+	pub fn parse(err: impl Display) -> Self {
+		Self::Parse(err.to_string())
+	}
+
+	// Whether the same request may succeed if it is sent again later.
+	pub const fn is_transient(&self) -> bool {
+		match self {
+			Self::Network(_) | Self::RateLimited { .. } => true,
+			Self::Status { status, .. } => matches!(status, 500 | 502 | 503 | 504),
+			_ => false,
+		}
+	}
+
+	// The bbox contains more nodes than the API returns at once.
+	pub fn is_too_many_nodes(&self) -> bool {
+		matches!(self, Self::Status { status: 400, body } if body.contains("too many nodes"))
+	}
+
+	// Time to wait before the given retry attempt (starting at 1), using exponential backoff unless the server specified it.
+	pub fn retry_delay(&self, attempt: u32) -> Duration {
+		match self {
+			Self::RateLimited { retry_after: Some(duration), .. } => *duration,
+			_ => RETRY_BASE_DELAY.saturating_mul(1 << attempt.saturating_sub(1).min(16)).min(MAX_RETRY_DELAY),
+		}
+	}
+}
+
+impl Display for OsmApiError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Status { status, body } if body.trim().is_empty() => write!(f, "request failed with status code {status}"),
+			Self::Status { status, body } => write!(f, "request failed with status code {status}: {}", body.trim()),
+			Self::Conflict(conflict) => write!(f, "{conflict}"),
+			Self::NotAuthenticated => write!(f, "not authenticated, please log in using the Auth tab"),
+			Self::AuthExpired => write!(f, "authentication expired, please log in again using the Auth tab"),
+			Self::Authorization(err) => write!(f, "authorization failed: {err}"),
+			Self::RateLimited { retry_after: Some(duration), .. } => write!(f, "rate limited by the server, try again in {} seconds", duration.as_secs()),
+			Self::RateLimited { status, retry_after: None } => write!(f, "rate limited by the server (status code {status}), try again later"),
+			Self::Network(err) => write!(f, "network error, please check your connection: {err}"),
+			Self::Parse(err) => write!(f, "failed to parse data: {err}"),
+			Self::Cancelled => write!(f, "cancelled"),
+		}
+	}
+}
+
+impl std::error::Error for OsmApiError {}
+
+impl From<quick_xml::SeError> for OsmApiError {
+	fn from(value: quick_xml::SeError) -> Self {
+		Self::parse(value)
+	}
+}
+
+impl From<quick_xml::DeError> for OsmApiError {
+	fn from(value: quick_xml::DeError) -> Self {
+		Self::parse(value)
+	}
+}
+
+impl From<quick_xml::Error> for OsmApiError {
+	fn from(value: quick_xml::Error) -> Self {
+		match value {
+			// the response is read while it is parsed
+			quick_xml::Error::Io(err) => Self::Network(err.to_string()),
+			err => Self::parse(err),
+		}
+	}
+}
+
+impl From<quick_xml::events::attributes::AttrError> for OsmApiError {
+	fn from(value: quick_xml::events::attributes::AttrError) -> Self {
+		Self::parse(value)
+	}
+}
+
+impl From<std::string::FromUtf8Error> for OsmApiError {
+	fn from(value: std::string::FromUtf8Error) -> Self {
+		Self::parse(value)
+	}
+}
+
+impl From<std::num::ParseIntError> for OsmApiError {
+	fn from(value: std::num::ParseIntError) -> Self {
+		Self::parse(value)
+	}
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl From<ureq::Error> for OsmApiError {
+	fn from(value: ureq::Error) -> Self {
+		match value {
+			ureq::Error::StatusCode(status) => Self::from_status(status, None, String::new()),
+			ureq::Error::Json(err) => Self::parse(err),
+			err => Self::Network(err.to_string()),
+		}
+	}
+}
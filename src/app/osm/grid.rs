@@ -0,0 +1,133 @@
+// Fixed grid of download tiles, so large areas can be downloaded in requests the API accepts.
+// The grid is aligned to 0°, so the same area always maps to the same tiles.
+
+use super::Bbox;
+use std::collections::HashSet;
+
+const TILE_SIZE: f64 = 0.02; // degrees, about 1.4 km wide and 2.2 km high in central Europe
+const EPSILON: f64 = 1e-9; // in tiles, so bboxes of tiles do not touch their neighbours due to rounding
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct Tile {
+	pub x: i32, // column, from the prime meridian eastwards
+	pub y: i32, // row, from the equator northwards
+}
+
+// Inclusive tile index ranges, tiles only touching the bbox at an edge are left out.
+#[allow(clippy::cast_possible_truncation)]
+fn ranges(bbox: &Bbox) -> ((i32, i32), (i32, i32)) {
+	let start = |x: f64| (x / TILE_SIZE + EPSILON).floor() as i32;
+	let end = |start: i32, x: f64| ((x / TILE_SIZE - EPSILON).ceil() as i32 - 1).max(start);
+
+	let (left, bottom) = (start(bbox.left), start(bbox.bottom));
+	((left, end(left, bbox.right)), (bottom, end(bottom, bbox.top)))
+}
+
+impl Tile {
+	pub fn bbox(self) -> Bbox {
+		let (x, y) = (f64::from(self.x), f64::from(self.y));
+		Bbox {
+			left: x * TILE_SIZE,
+			bottom: y * TILE_SIZE,
+			right: (x + 1.0) * TILE_SIZE,
+			top: (y + 1.0) * TILE_SIZE,
+		}
+	}
+
+	// Tiles intersecting the bbox, starting in the center so the visible area is loaded first.
+	pub fn covering(bbox: &Bbox) -> Vec<Self> {
+		let ((left, right), (bottom, top)) = ranges(bbox);
+		let mut tiles = (left..=right)
+			.flat_map(|x| (bottom..=top).map(move |y| Self { x, y }))
+			.collect::<Vec<_>>();
+
+		let center = (left + right, bottom + top); // doubled to stay in integers
+		tiles.sort_by_key(|t| (2 * t.x - center.0).pow(2) + (2 * t.y - center.1).pow(2));
+		tiles
+	}
+
+	// Same as covering(bbox).len(), without allocating.
+	#[allow(clippy::cast_sign_loss)]
+	pub fn count(bbox: &Bbox) -> usize {
+		let ((left, right), (bottom, top)) = ranges(bbox);
+		(right - left + 1) as usize * (top - bottom + 1) as usize
+	}
+
+	// Edges of the area covered by the tiles as (lon, lat) pairs, edges between two of the tiles are left out.
+	pub fn outline(tiles: &HashSet<Self>) -> Vec<[(f64, f64); 2]> {
+		let corner = |x: i32, y: i32| (f64::from(x) * TILE_SIZE, f64::from(y) * TILE_SIZE);
+		let mut edges = Vec::new();
+
+		for &Self { x, y } in tiles {
+			let missing = |dx: i32, dy: i32| !tiles.contains(&Self { x: x + dx, y: y + dy });
+
+			if missing(-1, 0) { edges.push([corner(x, y), corner(x, y + 1)]); }
+			if missing(1, 0) { edges.push([corner(x + 1, y), corner(x + 1, y + 1)]); }
+			if missing(0, -1) { edges.push([corner(x, y), corner(x + 1, y)]); }
+			if missing(0, 1) { edges.push([corner(x, y + 1), corner(x + 1, y + 1)]); }
+		}
+
+		edges
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn bbox(left: f64, bottom: f64, right: f64, top: f64) -> Bbox {
+		Bbox { left, bottom, right, top }
+	}
+
+	#[test]
+	fn covering() {
+		let tiles = Tile::covering(&bbox(10.01, 50.01, 10.05, 50.03));
+		assert_eq!(tiles.len(), 3 * 2);
+		assert_eq!(Tile::count(&bbox(10.01, 50.01, 10.05, 50.03)), tiles.len());
+		assert!(tiles.contains(&Tile { x: 500, y: 2500 }) && tiles.contains(&Tile { x: 502, y: 2501 }));
+
+		// the center tile comes first
+		assert_eq!(tiles[0].x, 501);
+
+		// southern and western hemisphere
+		assert_eq!(Tile::covering(&bbox(-0.01, -0.01, 0.01, 0.01)), [
+			Tile { x: -1, y: -1 }, Tile { x: -1, y: 0 }, Tile { x: 0, y: -1 }, Tile { x: 0, y: 0 },
+		]);
+	}
+
+	#[test]
+	fn edges() {
+		// a bbox matching a tile exactly does not touch its neighbours
+		let tile = Tile { x: 3, y: -7 };
+		assert_eq!(Tile::covering(&tile.bbox()), [tile]);
+
+		// empty bboxes still need a tile
+		assert_eq!(Tile::count(&bbox(0.03, 0.03, 0.03, 0.03)), 1);
+	}
+
+	#[test]
+	#[allow(clippy::float_cmp)]
+	fn outline() {
+		let mut tiles = HashSet::from([Tile { x: 0, y: 0 }]);
+		assert_eq!(Tile::outline(&tiles).len(), 4);
+
+		// the shared edge is not part of the outline
+		tiles.insert(Tile { x: 1, y: 0 });
+		let edges = Tile::outline(&tiles);
+		assert_eq!(edges.len(), 6);
+		assert!(!edges.iter().any(|[a, b]| a.0 == TILE_SIZE && b.0 == TILE_SIZE));
+
+		// a hole has an outline of its own
+		let tiles = (-1..=1).flat_map(|x| (-1..=1).map(move |y| Tile { x, y }))
+			.filter(|t| *t != Tile { x: 0, y: 0 })
+			.collect();
+		assert_eq!(Tile::outline(&tiles).len(), 3 * 4 + 4);
+	}
+
+	#[test]
+	fn tile_bbox() {
+		let bbox = Tile { x: 500, y: 2500 }.bbox();
+		assert!((bbox.left - 10.0).abs() < 1e-9 && (bbox.top - 50.02).abs() < 1e-9);
+		assert!(bbox.area() <= TILE_SIZE * TILE_SIZE + 1e-12);
+	}
+}
@@ -0,0 +1,520 @@
+// In-process stand-in for the OSM API 0.6, used to test OsmClient and the Worker without network access.
+// Every request is recorded, so tests can assert on the exact data that was sent.
+
+use super::auth::{query_params, Pkce};
+use super::{Account, OsmClient, OsmToken, TargetServer, UserDetails};
+use crate::app::editor::cache::{Element, ElementId};
+use crate::app::osmchange::{self, from_osmchange_id, to_osmchange_id, OsmChange, Tag};
+use quick_xml::de::from_str;
+use quick_xml::escape::escape;
+use osm_parser::{Coordinate, Id, Tags};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+pub const ACCESS_TOKEN: &str = "mock-access-token";
+pub const AUTH_CODE: &str = "mock-auth-code";
+pub const USER_ID: u64 = 1;
+pub const USER_NAME: &str = "mock-user";
+const FIRST_NEW_ID: Id = 1_000_000;
+
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+	pub method: String,
+	pub path: String, // including the query
+	pub headers: Vec<(String, String)>, // names are lowercase
+	pub body: String,
+}
+
+impl RecordedRequest {
+	pub fn header(&self, name: &str) -> Option<&str> {
+		self.headers.iter()
+			.find(|(k, _)| k == name)
+			.map(|(_, v)| v.as_str())
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+	pub status: u16,
+	pub headers: Vec<(String, String)>,
+	pub body: String,
+}
+
+impl MockResponse {
+	pub fn new(status: u16, body: impl Into<String>) -> Self {
+		Self { status, headers: Vec::new(), body: body.into() }
+	}
+
+	pub fn header(mut self, name: &str, value: &str) -> Self {
+		self.headers.push((name.into(), value.into()));
+		self
+	}
+
+	fn json(body: String) -> Self {
+		Self::new(200, body).header("content-type", "application/json")
+	}
+
+	fn xml(body: String) -> Self {
+		Self::new(200, body).header("content-type", "application/xml")
+	}
+}
+
+#[derive(Default)]
+struct MockState {
+	requests: Vec<RecordedRequest>,
+	queued: VecDeque<(String, String, MockResponse)>, // method, path prefix, response
+	history: HashMap<ElementId, Vec<Element>>, // all versions of an element, oldest first
+	deleted: HashSet<ElementId>,
+	next_changeset: u32,
+	next_id: Id,
+	code_challenge: Option<String>, // of the last authorization request
+	revoked: bool,
+}
+
+pub struct MockOsmApi {
+	pub addr: SocketAddr,
+	state: Arc<Mutex<MockState>>,
+	shutdown: Arc<AtomicBool>,
+}
+
+impl MockOsmApi {
+	pub fn start() -> Self {
+		let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind mock server");
+		let addr = listener.local_addr().unwrap();
+		let state = Arc::new(Mutex::new(MockState { next_changeset: 1, next_id: FIRST_NEW_ID, ..Default::default() }));
+		let shutdown = Arc::new(AtomicBool::new(false));
+
+		/* server thread */ {
+			let state = state.clone();
+			let shutdown = shutdown.clone();
+			std::thread::spawn(move || {
+				for stream in listener.incoming() {
+					if shutdown.load(Ordering::Relaxed) {
+						break;
+					}
+					if let Ok(stream) = stream {
+						handle_connection(stream, &state);
+					}
+				}
+			});
+		}
+
+		Self { addr, state, shutdown }
+	}
+
+	pub fn target_server(&self) -> TargetServer {
+		TargetServer {
+			name: "Mock server".into(),
+			host: self.addr.to_string(),
+			client_id: "mock-client-id".into(),
+			https: false,
+		}
+	}
+
+	// Returns a client for this server that is already authenticated.
+	pub fn client(&self) -> OsmClient {
+		let target_server = self.target_server();
+		let mut client = OsmClient::new(target_server.clone());
+		client.accounts.entry(target_server.base_url()).or_default().insert(Account {
+			user: UserDetails { id: USER_ID, display_name: USER_NAME.into() },
+			token: token(),
+		});
+		client
+	}
+
+	// Adds a new version of an element, which is returned by the map and element endpoints from now on.
+	pub fn add_element(&self, element: Element) {
+		let mut state = self.state.lock().unwrap();
+		let id = element.element_ref().element_id();
+		state.deleted.remove(&id);
+		state.history.entry(id).or_default().push(element);
+	}
+
+	// Responds to the next request matching the method and path prefix with the given response instead of the default one.
+	pub fn queue_response(&self, method: &str, path: &str, response: MockResponse) {
+		self.state.lock().unwrap().queued.push_back((method.into(), path.into(), response));
+	}
+
+	pub fn requests(&self) -> Vec<RecordedRequest> {
+		self.state.lock().unwrap().requests.clone()
+	}
+
+	pub fn latest(&self, id: &ElementId) -> Option<Element> {
+		self.state.lock().unwrap().latest(id).cloned()
+	}
+}
+
+impl Drop for MockOsmApi {
+	fn drop(&mut self) {
+		// wake up the server thread so it can exit
+		self.shutdown.store(true, Ordering::Relaxed);
+		let _ = TcpStream::connect(self.addr);
+	}
+}
+
+pub fn token() -> OsmToken {
+	OsmToken {
+		access_token: ACCESS_TOKEN.into(),
+		token_type: "Bearer".into(),
+		scope: "write_api".into(),
+		created_at: 0,
+	}
+}
+
+fn handle_connection(stream: TcpStream, state: &Mutex<MockState>) {
+	let Some(request) = read_request(&stream) else { return; };
+
+	let response = {
+		let mut state = state.lock().unwrap();
+		state.requests.push(request.clone());
+		state.respond(&request)
+	};
+
+	let _ = write_response(stream, &response);
+}
+
+fn read_request(stream: &TcpStream) -> Option<RecordedRequest> {
+	let mut reader = BufReader::new(stream);
+
+	let mut line = String::new();
+	reader.read_line(&mut line).ok()?;
+	let mut parts = line.split_whitespace();
+	let method = parts.next()?.to_owned();
+	let path = parts.next()?.to_owned();
+
+	let mut headers = Vec::new();
+	loop {
+		line.clear();
+		reader.read_line(&mut line).ok()?;
+		let Some((name, value)) = line.trim_end().split_once(':') else { break; };
+		headers.push((name.trim().to_lowercase(), value.trim().to_owned()));
+	}
+
+	let length = headers.iter()
+		.find(|(k, _)| k == "content-length")
+		.and_then(|(_, v)| v.parse().ok())
+		.unwrap_or(0);
+	let mut body = vec![0; length];
+	reader.read_exact(&mut body).ok()?;
+
+	Some(RecordedRequest { method, path, headers, body: String::from_utf8(body).ok()? })
+}
+
+fn write_response(mut stream: TcpStream, response: &MockResponse) -> std::io::Result<()> {
+	let mut head = format!("HTTP/1.1 {} Mock\r\ncontent-length: {}\r\nconnection: close\r\n", response.status, response.body.len());
+	for (name, value) in &response.headers {
+		let _ = write!(head, "{name}: {value}\r\n");
+	}
+	head.push_str("\r\n");
+
+	stream.write_all(head.as_bytes())?;
+	stream.write_all(response.body.as_bytes())?;
+	stream.flush()
+}
+
+impl MockState {
+	fn latest(&self, id: &ElementId) -> Option<&Element> {
+		if self.deleted.contains(id) {
+			return None;
+		}
+		self.history.get(id).and_then(|x| x.last())
+	}
+
+	fn respond(&mut self, request: &RecordedRequest) -> MockResponse {
+		if let Some(i) = self.queued.iter().position(|(method, path, _)| *method == request.method && request.path.starts_with(path.as_str())) {
+			return self.queued.remove(i).unwrap().2;
+		}
+
+		let path = request.path.split_once('?').map_or(request.path.as_str(), |(path, _)| path);
+		// elements are returned in the format of the extension, like the API does
+		let (path, json) = path.strip_suffix(".json").map_or((path, false), |x| (x, true));
+		let segments = path.trim_start_matches('/').split('/').collect::<Vec<_>>();
+
+		match (request.method.as_str(), segments.as_slice()) {
+			("GET", ["oauth2", "authorize"]) => {
+				// the user grants access right away
+				let params = query_params(request.path.split_once('?').map_or("", |(_, query)| query));
+				let param = |name: &str| params.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str()).unwrap_or_default();
+
+				self.code_challenge = Some(param("code_challenge").to_owned()).filter(|x| !x.is_empty());
+				let location = format!("{}?code={AUTH_CODE}&state={}", param("redirect_uri"), param("state"));
+				MockResponse::new(302, "").header("location", &location)
+			}
+			("POST", ["oauth2", "token"]) => {
+				let params = query_params(&request.body);
+				let verifier = params.iter().find(|(k, _)| k == "code_verifier").map(|(_, v)| v.clone());
+				if let Some(challenge) = self.code_challenge.take()
+					&& verifier.is_none_or(|x| Pkce::from_verifier(x).challenge != challenge)
+				{
+					return MockResponse::new(400, r#"{"error":"invalid_grant"}"#);
+				}
+
+				self.revoked = false;
+				let token = token();
+				MockResponse::json(format!(
+					r#"{{"access_token":"{}","token_type":"{}","scope":"{}","created_at":{}}}"#,
+					token.access_token, token.token_type, token.scope, token.created_at,
+				))
+			}
+			("POST", ["oauth2", "revoke"]) => {
+				if query_params(&request.body).iter().any(|(k, v)| k == "token" && v == ACCESS_TOKEN) {
+					self.revoked = true;
+				}
+				MockResponse::json("{}".into())
+			}
+			(_, ["api", "0.6", ..]) if request.header("authorization").is_some_and(|x| self.revoked || x != format!("Bearer {ACCESS_TOKEN}")) => {
+				MockResponse::new(401, "Couldn't authenticate you")
+			}
+			("GET", ["api", "0.6", "user", "details"]) => {
+				if request.header("authorization").is_none() {
+					return MockResponse::new(401, "Couldn't authenticate you");
+				}
+				MockResponse::json(format!(r#"{{"version":"0.6","generator":"mock","user":{{"id":{USER_ID},"display_name":"{USER_NAME}","account_created":"2025-01-01T00:00:00Z"}}}}"#))
+			}
+			("GET", ["api", "0.6", "map"]) => {
+				let mut ids = self.history.keys().filter(|id| !self.deleted.contains(*id)).collect::<Vec<_>>();
+				ids.sort_by_key(|id| (matches!(id, ElementId::Way(_)), *id.id_ref()));
+				let elements = ids.into_iter().filter_map(|id| self.latest(id)).collect::<Vec<_>>();
+				osm_response(&elements, json)
+			}
+			("GET", ["api", "0.6", "node", id]) => self.element_response(id, ElementId::Node, false, json),
+			("GET", ["api", "0.6", "way", id]) => self.element_response(id, ElementId::Way, false, json),
+			("GET", ["api", "0.6", "way", id, "full"]) => self.element_response(id, ElementId::Way, true, json),
+			("GET", ["api", "0.6", kind, id, "history"]) => {
+				let id = match (*kind, id.parse()) {
+					("node", Ok(id)) => ElementId::Node(id),
+					(_, Ok(id)) => ElementId::Way(id),
+					_ => return MockResponse::new(400, "invalid id"),
+				};
+				match self.history.get(&id) {
+					Some(versions) => osm_response(&versions.iter().collect::<Vec<_>>(), json),
+					None => MockResponse::new(404, ""),
+				}
+			}
+			("PUT", ["api", "0.6", "changeset", "create"]) => {
+				if request.header("authorization").is_none() {
+					return MockResponse::new(401, "Couldn't authenticate you");
+				}
+				let id = self.next_changeset;
+				self.next_changeset += 1;
+				MockResponse::new(200, id.to_string()).header("content-type", "text/plain")
+			}
+			("POST", ["api", "0.6", "changeset", _, "upload"]) => {
+				match from_str::<OsmChange>(&request.body) {
+					Ok(osmchange) => self.upload(&osmchange),
+					Err(err) => MockResponse::new(400, format!("Cannot parse valid osmChange: {err}")),
+				}
+			}
+			("PUT", ["api", "0.6", "changeset", _, "close"]) => MockResponse::new(200, ""),
+			_ => MockResponse::new(404, ""),
+		}
+	}
+
+	fn element_response(&self, id: &str, kind: fn(Id) -> ElementId, full: bool, json: bool) -> MockResponse {
+		let Some(id) = id.parse().ok().map(kind) else {
+			return MockResponse::new(400, "invalid id");
+		};
+		let Some(element) = self.latest(&id) else {
+			return MockResponse::new(if self.history.contains_key(&id) { 410 } else { 404 }, "");
+		};
+
+		let mut elements = Vec::new();
+		if full && let Element::Way(way) = element {
+			elements.extend(way.nodes.iter().filter_map(|id| self.latest(&ElementId::Node(*id))));
+		}
+		elements.push(element);
+
+		osm_response(&elements, json)
+	}
+
+	// Applies the osmChange like the API would and returns the diffResult, or a 409 on version mismatches.
+	fn upload(&mut self, osmchange: &OsmChange) -> MockResponse {
+		let modified = osmchange.modify.iter().flat_map(|x| {
+			x.node.iter().map(|n| (ElementId::Node(from_osmchange_id(n.id)), n.version))
+				.chain(x.way.iter().map(|w| (ElementId::Way(from_osmchange_id(w.id)), w.version)))
+		});
+		let deleted = osmchange.delete.iter().flat_map(|x| {
+			x.way.iter().map(|w| (ElementId::Way(from_osmchange_id(w.id)), w.version))
+				.chain(x.node.iter().map(|n| (ElementId::Node(from_osmchange_id(n.id)), n.version)))
+		}).collect::<Vec<_>>();
+
+		// the upload is atomic, nothing is applied if any element is outdated
+		for (id, version) in modified.chain(deleted.iter().cloned()) {
+			if let Err(resp) = self.check_version(&id, version) {
+				return resp;
+			}
+		}
+
+		let mut diff = String::from(r#"<?xml version="1.0" encoding="UTF-8"?><diffResult version="0.6" generator="mock">"#);
+		let mut new_ids = HashMap::new();
+
+		if let Some(create) = &osmchange.create {
+			for node in &create.node {
+				let id = self.new_id(&mut new_ids, ElementId::Node(from_osmchange_id(node.id)));
+				self.add(Element::Node(convert_node(node, id, 1)));
+				let _ = write!(diff, r#"<node old_id="{}" new_id="{id}" new_version="1"/>"#, node.id);
+			}
+			for way in &create.way {
+				let id = self.new_id(&mut new_ids, ElementId::Way(from_osmchange_id(way.id)));
+				self.add(Element::Way(convert_way(way, id, 1, &new_ids)));
+				let _ = write!(diff, r#"<way old_id="{}" new_id="{id}" new_version="1"/>"#, way.id);
+			}
+		}
+
+		if let Some(modify) = &osmchange.modify {
+			for node in &modify.node {
+				self.add(Element::Node(convert_node(node, from_osmchange_id(node.id), node.version + 1)));
+				let _ = write!(diff, r#"<node old_id="{}" new_id="{}" new_version="{}"/>"#, node.id, node.id, node.version + 1);
+			}
+			for way in &modify.way {
+				self.add(Element::Way(convert_way(way, from_osmchange_id(way.id), way.version + 1, &new_ids)));
+				let _ = write!(diff, r#"<way old_id="{}" new_id="{}" new_version="{}"/>"#, way.id, way.id, way.version + 1);
+			}
+		}
+
+		for (id, _) in deleted {
+			let (kind, osmchange_id) = match &id {
+				ElementId::Node(x) => ("node", to_osmchange_id(*x)),
+				ElementId::Way(x) => ("way", to_osmchange_id(*x)),
+			};
+			let _ = write!(diff, r#"<{kind} old_id="{osmchange_id}"/>"#);
+			self.deleted.insert(id);
+		}
+
+		diff.push_str("</diffResult>");
+		MockResponse::xml(diff)
+	}
+
+	fn new_id(&mut self, new_ids: &mut HashMap<ElementId, Id>, placeholder: ElementId) -> Id {
+		let id = self.next_id;
+		self.next_id += 1;
+		new_ids.insert(placeholder, id);
+		id
+	}
+
+	fn add(&mut self, element: Element) {
+		self.history.entry(element.element_ref().element_id()).or_default().push(element);
+	}
+
+	fn check_version(&self, id: &ElementId, provided: u32) -> Result<(), MockResponse> {
+		let Some(server_version) = self.latest(id).map(Element::version) else {
+			return Err(MockResponse::new(404, ""));
+		};
+		if provided == server_version {
+			return Ok(());
+		}
+
+		let (kind, id) = match *id {
+			ElementId::Node(id) => ("Node", id),
+			ElementId::Way(id) => ("Way", id),
+		};
+		Err(MockResponse::new(409, format!("Version mismatch: Provided {provided}, server had: {server_version} of {kind} {id}")))
+	}
+}
+
+fn convert_tags(tags: &[Tag]) -> Tags {
+	let mut result = Tags::default();
+	for tag in tags {
+		result.insert(tag.k.clone(), tag.v.clone());
+	}
+	result
+}
+
+fn convert_node(node: &osmchange::Node, id: Id, version: u32) -> osm_parser::Node {
+	osm_parser::Node {
+		id,
+		pos: Coordinate::new(node.lat, node.lon),
+		tags: convert_tags(&node.tags),
+		version,
+		changeset: node.changeset,
+	}
+}
+
+fn convert_way(way: &osmchange::Way, id: Id, version: u32, new_ids: &HashMap<ElementId, Id>) -> osm_parser::Way {
+	osm_parser::Way {
+		id,
+		nodes: way.nodes.iter()
+			.map(|nd| from_osmchange_id(nd.r#ref))
+			.map(|id| new_ids.get(&ElementId::Node(id)).copied().unwrap_or(id))
+			.collect(),
+		tags: convert_tags(&way.tags),
+		version,
+		changeset: way.changeset,
+	}
+}
+
+fn json_string(value: &str) -> String {
+	let mut result = String::from("\"");
+	for c in value.chars() {
+		match c {
+			'"' => result.push_str("\\\""),
+			'\\' => result.push_str("\\\\"),
+			c if c.is_control() => { let _ = write!(result, "\\u{:04x}", c as u32); }
+			c => result.push(c),
+		}
+	}
+	result.push('"');
+	result
+}
+
+fn json_tags(tags: &Tags) -> String {
+	let tags = tags.iter()
+		.map(|(k, v)| format!("{}:{}", json_string(k), json_string(v)))
+		.collect::<Vec<_>>();
+	format!("{{{}}}", tags.join(","))
+}
+
+fn osm_response(elements: &[&Element], json: bool) -> MockResponse {
+	if json {
+		MockResponse::json(osm_json(elements))
+	} else {
+		MockResponse::xml(osm_xml(elements))
+	}
+}
+
+// Line breaks are escaped as well, they would be normalized to spaces otherwise.
+fn xml_attribute(value: &str) -> String {
+	escape(value).replace('\n', "&#10;")
+}
+
+fn xml_tags(tags: &Tags) -> String {
+	tags.iter()
+		.map(|(k, v)| format!(r#"<tag k="{}" v="{}"/>"#, xml_attribute(k), xml_attribute(v)))
+		.collect()
+}
+
+// Serializes elements in the XML format of the API.
+pub fn osm_xml(elements: &[&Element]) -> String {
+	let elements = elements.iter().map(|element| match element {
+		Element::Node(n) => format!(
+			r#"<node id="{}" visible="true" version="{}" changeset="{}" timestamp="2025-01-01T00:00:00Z" user="mock" uid="1" lat="{}" lon="{}">{}</node>"#,
+			n.id, n.version, n.changeset, n.pos.lat, n.pos.lon, xml_tags(&n.tags),
+		),
+		Element::Way(w) => format!(
+			r#"<way id="{}" visible="true" version="{}" changeset="{}" timestamp="2025-01-01T00:00:00Z" user="mock" uid="1">{}{}</way>"#,
+			w.id, w.version, w.changeset, w.nodes.iter().map(|id| format!(r#"<nd ref="{id}"/>"#)).collect::<String>(), xml_tags(&w.tags),
+		),
+	}).collect::<String>();
+
+	format!(r#"<?xml version="1.0" encoding="UTF-8"?><osm version="0.6" generator="mock" copyright="" attribution="" license="">{elements}</osm>"#)
+}
+
+// Serializes elements in the JSON format of the API.
+pub fn osm_json(elements: &[&Element]) -> String {
+	let elements = elements.iter().map(|element| match element {
+		Element::Node(n) => format!(
+			r#"{{"type":"node","id":{},"lat":{},"lon":{},"timestamp":"2025-01-01T00:00:00Z","version":{},"changeset":{},"user":"mock","uid":1,"tags":{}}}"#,
+			n.id, n.pos.lat, n.pos.lon, n.version, n.changeset, json_tags(&n.tags),
+		),
+		Element::Way(w) => format!(
+			r#"{{"type":"way","id":{},"timestamp":"2025-01-01T00:00:00Z","version":{},"changeset":{},"user":"mock","uid":1,"nodes":[{}],"tags":{}}}"#,
+			w.id, w.version, w.changeset, w.nodes.iter().map(ToString::to_string).collect::<Vec<_>>().join(","), json_tags(&w.tags),
+		),
+	}).collect::<Vec<_>>();
+
+	format!(r#"{{"version":"0.6","generator":"mock","copyright":"","attribution":"","license":"","elements":[{}]}}"#, elements.join(","))
+}
@@ -0,0 +1,278 @@
+// Streaming reader for elements in the XML format of the read endpoints of the API, like /map and /way/{id}/full.
+// Elements are converted while the response is received, so large downloads are never kept in memory as text.
+
+use super::{OsmApiError, OsmResult};
+use osm_parser::{Coordinate, OsmData, Tags};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::fmt::Display;
+use std::io::BufRead;
+use std::str::FromStr;
+
+const API_VERSION: &str = "0.6";
+const PROGRESS_INTERVAL: usize = 5000; // elements between two progress reports
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct MapProgress {
+	pub bytes: u64,
+	pub total_bytes: Option<u64>, // unknown for compressed or chunked responses
+	pub elements: usize,
+}
+
+impl MapProgress {
+	#[allow(clippy::cast_precision_loss)]
+	pub fn fraction(&self) -> Option<f32> {
+		self.total_bytes
+			.filter(|x| *x > 0)
+			.map(|total| (self.bytes as f32 / total as f32).min(1.0))
+	}
+}
+
+impl Display for MapProgress {
+	#[allow(clippy::cast_precision_loss)]
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let mb = |bytes: u64| bytes as f64 / 1_000_000.0;
+		match self.total_bytes {
+			Some(total) => write!(f, "{:.1} of {:.1} MB, {} elements", mb(self.bytes), mb(total), self.elements),
+			None => write!(f, "{:.1} MB, {} elements", mb(self.bytes), self.elements),
+		}
+	}
+}
+
+// Element whose start tag was read, completed by its child tags.
+enum Current {
+	None,
+	Node(osm_parser::Node),
+	Way(osm_parser::Way),
+	Skipped, // deleted versions and relations, which are not supported by the editor yet
+}
+
+impl Current {
+	const fn tags_mut(&mut self) -> Option<&mut Tags> {
+		match self {
+			Self::Node(node) => Some(&mut node.tags),
+			Self::Way(way) => Some(&mut way.tags),
+			Self::None | Self::Skipped => None,
+		}
+	}
+}
+
+fn attribute(e: &BytesStart, name: &str) -> OsmResult<Option<String>> {
+	match e.try_get_attribute(name)? {
+		Some(attr) => Ok(Some(attr.unescape_value()?.into_owned())),
+		None => Ok(None),
+	}
+}
+
+fn required<T: FromStr>(e: &BytesStart, name: &str) -> OsmResult<T>
+where
+	T::Err: Display,
+{
+	attribute(e, name)?
+		.ok_or_else(|| OsmApiError::parse(format!("missing attribute {name} of {}", String::from_utf8_lossy(e.name().as_ref()))))?
+		.parse()
+		.map_err(OsmApiError::parse)
+}
+
+fn start_element(e: &BytesStart) -> OsmResult<Current> {
+	if attribute(e, "visible")?.as_deref() == Some("false") {
+		return Ok(Current::Skipped); // deleted versions have no coordinates and nodes
+	}
+
+	Ok(match e.name().as_ref() {
+		b"node" => Current::Node(osm_parser::Node {
+			id: required(e, "id")?,
+			pos: Coordinate::new(required(e, "lat")?, required(e, "lon")?),
+			tags: Tags::default(),
+			version: required(e, "version")?,
+			changeset: required(e, "changeset")?,
+		}),
+		b"way" => Current::Way(osm_parser::Way {
+			id: required(e, "id")?,
+			nodes: Vec::new(),
+			tags: Tags::default(),
+			version: required(e, "version")?,
+			changeset: required(e, "changeset")?,
+		}),
+		_ => Current::Skipped,
+	})
+}
+
+// Reads the response of /map or any other endpoint returning elements, progress is reported every few thousand elements.
+pub fn read_osm_data(reader: impl BufRead, total_bytes: Option<u64>, mut on_progress: impl FnMut(MapProgress)) -> OsmResult<OsmData> {
+	let mut reader = Reader::from_reader(reader);
+	reader.config_mut().expand_empty_elements = true;
+
+	let mut buf = Vec::new();
+	let mut data = OsmData::default();
+	let mut current = Current::None;
+	let mut progress = MapProgress { total_bytes, ..Default::default() };
+	let mut found_root = false;
+
+	loop {
+		match reader.read_event_into(&mut buf)? {
+			Event::Start(e) => match e.name().as_ref() {
+				b"osm" => {
+					let version = attribute(&e, "version")?.unwrap_or_default();
+					if version != API_VERSION {
+						return Err(OsmApiError::parse(format!("unsupported API version {version}")));
+					}
+					found_root = true;
+				}
+				b"node" | b"way" | b"relation" => current = start_element(&e)?,
+				b"nd" => if let Current::Way(way) = &mut current {
+					way.nodes.push(required(&e, "ref")?);
+				},
+				b"tag" => if let Some(tags) = current.tags_mut() {
+					tags.insert(required(&e, "k")?, required(&e, "v")?);
+				},
+				_ => {}
+			},
+			Event::End(e) if matches!(e.name().as_ref(), b"node" | b"way" | b"relation") => {
+				match std::mem::replace(&mut current, Current::None) {
+					Current::Node(node) => { data.nodes.insert(node.id, node); }
+					Current::Way(way) => { data.ways.insert(way.id, way); }
+					Current::None | Current::Skipped => {}
+				}
+
+				progress.elements += 1;
+				if progress.elements % PROGRESS_INTERVAL == 0 {
+					progress.bytes = reader.buffer_position();
+					on_progress(progress);
+				}
+			}
+			Event::Eof => break,
+			_ => {}
+		}
+		buf.clear();
+	}
+
+	if !found_root {
+		return Err(OsmApiError::parse("missing osm element"));
+	}
+
+	progress.bytes = reader.buffer_position();
+	on_progress(progress);
+	Ok(data)
+}
+
+#[cfg(all(test, not(target_family = "wasm")))]
+mod tests {
+	use super::*;
+	use crate::app::editor::cache::Element;
+	use crate::app::osm::mock::{osm_json, osm_xml};
+	use osm_parser::types::raw;
+
+	fn parse_osm_data(text: &str) -> OsmResult<OsmData> {
+		read_osm_data(text.as_bytes(), None, |_| {})
+	}
+
+	const XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osm version="0.6" generator="openstreetmap-cgimap 2.0.1" copyright="OpenStreetMap and contributors" attribution="http://www.openstreetmap.org/copyright" license="http://opendatacommons.org/licenses/odbl/1-0/">
+ <bounds minlat="50.0590000" minlon="10.2160000" maxlat="50.0600000" maxlon="10.2170000"/>
+ <node id="1" visible="true" version="3" changeset="12345" timestamp="2025-01-01T00:00:00Z" user="mapper" uid="1" lat="50.0595613" lon="10.2168379">
+  <tag k="amenity" v="bench"/>
+  <tag k="name" v="Schulstraße &amp; &lt;Bahnhof&gt;"/>
+ </node>
+ <node id="2" visible="true" version="1" changeset="12345" timestamp="2025-01-01T00:00:00Z" user="mapper" uid="1" lat="50.0596" lon="10.2169"/>
+ <way id="10" visible="true" version="7" changeset="12346" timestamp="2025-01-01T00:00:00Z" user="mapper" uid="1">
+  <nd ref="1"/>
+  <nd ref="2"/>
+  <tag k="highway" v="footway"/>
+ </way>
+ <relation id="100" visible="true" version="2" changeset="12347" timestamp="2025-01-01T00:00:00Z" user="mapper" uid="1">
+  <member type="way" ref="10" role=""/>
+  <member type="node" ref="1" role="stop"/>
+  <tag k="type" v="route"/>
+ </relation>
+</osm>"#;
+
+	const JSON: &str = r#"{"version":"0.6","generator":"openstreetmap-cgimap 2.0.1","copyright":"OpenStreetMap and contributors","attribution":"http://www.openstreetmap.org/copyright","license":"http://opendatacommons.org/licenses/odbl/1-0/","bounds":{"minlat":50.059,"minlon":10.216,"maxlat":50.06,"maxlon":10.217},"elements":[
+{"type":"node","id":1,"lat":50.0595613,"lon":10.2168379,"timestamp":"2025-01-01T00:00:00Z","version":3,"changeset":12345,"user":"mapper","uid":1,"tags":{"amenity":"bench","name":"Schulstraße & <Bahnhof>"}},
+{"type":"node","id":2,"lat":50.0596,"lon":10.2169,"timestamp":"2025-01-01T00:00:00Z","version":1,"changeset":12345,"user":"mapper","uid":1},
+{"type":"way","id":10,"timestamp":"2025-01-01T00:00:00Z","version":7,"changeset":12346,"user":"mapper","uid":1,"nodes":[1,2],"tags":{"highway":"footway"}},
+{"type":"relation","id":100,"timestamp":"2025-01-01T00:00:00Z","version":2,"changeset":12347,"user":"mapper","uid":1,"members":[{"type":"way","ref":10,"role":""},{"type":"node","ref":1,"role":"stop"}],"tags":{"type":"route"}}
+]}"#;
+
+	fn from_json(text: &str) -> OsmData {
+		serde_json::from_str::<raw::RawOsmData>(text).unwrap().try_into().unwrap()
+	}
+
+	fn assert_data_eq(data: &OsmData, expected: &OsmData) {
+		assert_eq!(data.nodes.len(), expected.nodes.len());
+		for (id, n) in &expected.nodes {
+			let node = data.nodes.get(id).unwrap_or_else(|| panic!("node {id} missing"));
+			assert_eq!((node.id, node.version, node.changeset), (n.id, n.version, n.changeset));
+			// serde_json may round the last digit differently
+			assert!((node.pos.lat - n.pos.lat).abs() < 1e-12 && (node.pos.lon - n.pos.lon).abs() < 1e-12, "node {id} moved");
+			assert_eq!(node.tags, n.tags);
+		}
+
+		assert_eq!(data.ways.len(), expected.ways.len());
+		for (id, w) in &expected.ways {
+			let way = data.ways.get(id).unwrap_or_else(|| panic!("way {id} missing"));
+			assert_eq!((way.id, way.version, way.changeset), (w.id, w.version, w.changeset));
+			assert_eq!(way.nodes, w.nodes);
+			assert_eq!(way.tags, w.tags);
+		}
+	}
+
+	#[test]
+	fn parse_map() {
+		let data = parse_osm_data(XML).unwrap();
+		assert_eq!(data.nodes[&1].tags.get("name").unwrap(), "Schulstraße & <Bahnhof>");
+		assert_eq!(data.ways[&10].nodes, [1, 2]);
+		// the tags of the relation are not added to other elements
+		assert!(data.ways[&10].tags.get("type").is_none());
+	}
+
+	#[test]
+	fn parity_with_json() {
+		assert_data_eq(&parse_osm_data(XML).unwrap(), &from_json(JSON));
+	}
+
+	#[test]
+	fn parity_with_json_of_mock() {
+		let mut tags = Tags::default();
+		tags.insert("name".into(), "\"Quotes\" & 'apostrophes' <>".into());
+		tags.insert("note".into(), "line\nbreak".into());
+
+		let elements = [
+			Element::Node(osm_parser::Node { id: 1, pos: Coordinate::new(-33.868_82, 151.209_29), tags: tags.clone(), version: 1, changeset: 1 }),
+			Element::Node(osm_parser::Node { id: 2, pos: Coordinate::new(0.0, -0.000_001), tags: Tags::default(), version: 4, changeset: 2 }),
+			Element::Way(osm_parser::Way { id: 3, nodes: vec![2, 1, 2], tags, version: 2, changeset: 3 }),
+		];
+		let elements = elements.iter().collect::<Vec<_>>();
+
+		assert_data_eq(&parse_osm_data(&osm_xml(&elements)).unwrap(), &from_json(&osm_json(&elements)));
+	}
+
+	#[test]
+	fn progress() {
+		let elements = (1..=12_000)
+			.map(|id| Element::Node(osm_parser::Node { id, pos: Coordinate::new(50.0, 10.0), tags: Tags::default(), version: 1, changeset: 1 }))
+			.collect::<Vec<_>>();
+		let xml = osm_xml(&elements.iter().collect::<Vec<_>>());
+
+		let mut reports = Vec::new();
+		let data = read_osm_data(xml.as_bytes(), Some(xml.len() as u64), |x| reports.push(x)).unwrap();
+		assert_eq!(data.nodes.len(), 12_000);
+
+		assert_eq!(reports.iter().map(|x| x.elements).collect::<Vec<_>>(), [5000, 10_000, 12_000]);
+		assert!(reports.windows(2).all(|x| x[0].bytes < x[1].bytes));
+		assert_eq!(reports.last().unwrap().fraction(), Some(1.0));
+	}
+
+	#[test]
+	fn deleted_versions() {
+		let xml = r#"<osm version="0.6"><node id="1" visible="false" version="2" changeset="2"/></osm>"#;
+		assert!(parse_osm_data(xml).unwrap().nodes.is_empty());
+	}
+
+	#[test]
+	fn invalid() {
+		assert!(matches!(parse_osm_data(r#"<osm version="0.7"/>"#), Err(OsmApiError::Parse(_))));
+		assert!(matches!(parse_osm_data(r#"<osm version="0.6"><node id="1"/></osm>"#), Err(OsmApiError::Parse(_))));
+		assert!(matches!(parse_osm_data("Internal Server Error"), Err(OsmApiError::Parse(_))));
+	}
+}
@@ -2,36 +2,69 @@
 // todo: find a way to reduce number of structs and conversions
 
 use super::editor::cache::Change;
-use quick_xml::{se::Serializer, SeError};
+use quick_xml::{de::from_str, se::Serializer, DeError, SeError};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use indexmap::IndexMap;
 
 pub type Id = i64;
 
+// Elements that do not exist on the server yet use local IDs counted down from osm_parser::Id::MAX.
+// In the osmChange, they are represented by negative placeholder IDs starting at -1.
+#[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+pub const fn to_osmchange_id(id: osm_parser::Id) -> Id {
+	if is_placeholder_id(id) {
+		-((osm_parser::Id::MAX - id) as Id) - 1
+	} else {
+		id as Id
+	}
+}
+
+#[allow(clippy::cast_sign_loss)]
+pub const fn from_osmchange_id(id: Id) -> osm_parser::Id {
+	if id < 0 {
+		osm_parser::Id::MAX - (-(id + 1)) as osm_parser::Id
+	} else {
+		id as osm_parser::Id
+	}
+}
+
+#[allow(clippy::cast_sign_loss)]
+pub const fn is_placeholder_id(id: osm_parser::Id) -> bool {
+	id > Id::MAX as osm_parser::Id
+}
+
+// Blocks are serialized in field order, the API processes them from top to bottom:
+// created elements must exist before they can be referenced, and deleted elements must no longer be referenced.
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct OsmChange {
+	#[serde(rename = "@version")]
+	pub version: String,
 	#[serde(rename = "@generator")]
 	pub generator: String,
-	//pub create: Option<Create>,
+	pub create: Option<Create>,
 	pub modify: Option<Modify>,
-	//pub delete: Option<Delete>,
+	pub delete: Option<Delete>,
 }
 
-/*#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Create {
+	#[serde(default)]
 	pub node: Vec<Node>,
+	#[serde(default)]
 	pub way: Vec<Way>,
 }
 
 impl Create {
-	pub fn is_empty(&self) -> bool {
+	pub const fn is_empty(&self) -> bool {
 		self.node.is_empty() && self.way.is_empty()
 	}
-}*/
+}
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Modify {
+	#[serde(default)]
 	pub node: Vec<Node>,
+	#[serde(default)]
 	pub way: Vec<Way>,
 }
 
@@ -41,17 +74,23 @@ impl Modify {
 	}
 }
 
-/*#[derive(Debug, Default, Serialize, Deserialize)]
+// Ways are deleted before nodes, so that the nodes are no longer in use.
+// With if-unused, the server skips elements that are still referenced by others instead of failing the upload.
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Delete {
-	pub node: Vec<Node>,
+	#[serde(rename = "@if-unused", skip_serializing_if = "Option::is_none")]
+	pub if_unused: Option<bool>,
+	#[serde(default)]
 	pub way: Vec<Way>,
+	#[serde(default)]
+	pub node: Vec<Node>,
 }
 
 impl Delete {
-	pub fn is_empty(&self) -> bool {
+	pub const fn is_empty(&self) -> bool {
 		self.node.is_empty() && self.way.is_empty()
 	}
-}*/
+}
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Node {
@@ -61,7 +100,11 @@ pub struct Node {
 	pub changeset: u64,
 	#[serde(rename = "@version")]
 	pub version: u32,
-	#[serde(rename = "tag")]
+	#[serde(rename = "@lat")]
+	pub lat: f64,
+	#[serde(rename = "@lon")]
+	pub lon: f64,
+	#[serde(rename = "tag", default)]
 	pub tags: Vec<Tag>,
 }
 
@@ -73,16 +116,32 @@ pub struct Way {
 	pub changeset: u64,
 	#[serde(rename = "@version")]
 	pub version: u32,
-	#[serde(rename = "tag")]
+	#[serde(rename = "nd", default)]
+	pub nodes: Vec<Nd>,
+	#[serde(rename = "tag", default)]
 	pub tags: Vec<Tag>,
 }
 
+impl From<&osm_parser::Node> for Node {
+	fn from(value: &osm_parser::Node) -> Self {
+		Self {
+			id: to_osmchange_id(value.id),
+			changeset: value.changeset,
+			version: value.version,
+			lat: value.pos.lat,
+			lon: value.pos.lon,
+			tags: value.tags.iter().map(Into::into).collect(),
+		}
+	}
+}
+
 impl From<&osm_parser::Way> for Way {
 	fn from(value: &osm_parser::Way) -> Self {
 		Self {
-			id: value.id.try_into().expect("way id should fit into i64"),
+			id: to_osmchange_id(value.id),
 			changeset: value.changeset,
 			version: value.version,
+			nodes: value.nodes.iter().map(|id| Nd { r#ref: to_osmchange_id(*id) }).collect(),
 			tags: value.tags.iter().map(Into::into).collect(),
 		}
 	}
@@ -104,34 +163,98 @@ impl From<(&String, &String)> for Tag {
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Nd {
-	r#ref: Id,
+	#[serde(rename = "@ref")]
+	pub r#ref: Id,
+}
+
+// Net effect of all changes to a single element.
+enum NetChange<'a, T> {
+	Created(&'a T),
+	Modified(&'a T),
+	Deleted(&'a T),
+	Discarded, // created and deleted again
+}
+
+impl<'a, T> NetChange<'a, T> {
+	const fn update(prev: Option<Self>, element: &'a T) -> Self {
+		match prev {
+			Some(Self::Created(_)) => Self::Created(element),
+			_ => Self::Modified(element),
+		}
+	}
+
+	const fn delete(prev: Option<Self>, element: &'a T) -> Self {
+		match prev {
+			Some(Self::Created(_) | Self::Discarded) => Self::Discarded,
+			_ => Self::Deleted(element),
+		}
+	}
 }
 
 impl OsmChange {
 	pub fn from(changes: &Vec<Change>) -> Self {
-		let mut modified_ways = HashMap::new();
-
-		//let create = Create::default();
-		let mut modify = Modify::default();
-		//let delete = Delete::default();
+		let mut nodes = IndexMap::<_, NetChange<osm_parser::Node>>::new();
+		let mut ways = IndexMap::<_, NetChange<osm_parser::Way>>::new();
 
 		for change in changes {
 			match change {
+				Change::CreateNode(node) => {
+					nodes.insert(node.id, NetChange::Created(node));
+				}
+				Change::CreateWay(way) => {
+					ways.insert(way.id, NetChange::Created(way));
+				}
+				Change::UpdateNode(_, node) => {
+					let prev = nodes.shift_remove(&node.id);
+					nodes.insert(node.id, NetChange::update(prev, node));
+				}
 				Change::UpdateWay(_, way) => {
-					modified_ways.insert(way.id, way);
+					let prev = ways.shift_remove(&way.id);
+					ways.insert(way.id, NetChange::update(prev, way));
+				}
+				Change::DeleteNode(node) => {
+					let prev = nodes.shift_remove(&node.id);
+					nodes.insert(node.id, NetChange::delete(prev, node));
 				}
+				Change::DeleteWay(way) => {
+					let prev = ways.shift_remove(&way.id);
+					ways.insert(way.id, NetChange::delete(prev, way));
+				}
+			}
+		}
+
+		let mut create = Create::default();
+		let mut modify = Modify::default();
+		let mut delete = Delete { if_unused: Some(true), ..Default::default() };
+
+		for change in nodes.into_values() {
+			match change {
+				NetChange::Created(node) => create.node.push(node.into()),
+				NetChange::Modified(node) => modify.node.push(node.into()),
+				NetChange::Deleted(node) => delete.node.push(node.into()),
+				NetChange::Discarded => {},
 			}
 		}
 
-		for way in modified_ways.into_values() {
-			modify.way.push(way.into());
+		for change in ways.into_values() {
+			match change {
+				NetChange::Created(way) => create.way.push(way.into()),
+				NetChange::Modified(way) => modify.way.push(way.into()),
+				NetChange::Deleted(way) => delete.way.push(way.into()),
+				NetChange::Discarded => {},
+			}
 		}
 
+		// placeholder ids count down from -1 in creation order
+		create.node.sort_unstable_by_key(|x| std::cmp::Reverse(x.id));
+		create.way.sort_unstable_by_key(|x| std::cmp::Reverse(x.id));
+
 		Self {
+			version: "0.6".into(),
 			generator: crate::USER_AGENT.into(),
-			//create: if create.is_empty() { None } else { Some(create) },
+			create: if create.is_empty() { None } else { Some(create) },
 			modify: if modify.is_empty() { None } else { Some(modify) },
-			//delete: if delete.is_empty() { None } else { Some(delete) },
+			delete: if delete.is_empty() { None } else { Some(delete) },
 		}
 	}
 
@@ -143,13 +266,198 @@ impl OsmChange {
 		Ok(buffer)
 	}
 
+	// Assigns the changeset to all elements. The versions are left as-is, since the API expects the version being modified.
 	pub fn prepare_upload(&mut self, changeset_id: u64) {
+		if let Some(create) = self.create.as_mut() {
+			create.node.iter_mut().for_each(|x| x.changeset = changeset_id);
+			create.way.iter_mut().for_each(|x| x.changeset = changeset_id);
+		}
+
 		if let Some(modify) = self.modify.as_mut() {
-			modify.node.iter_mut().for_each(|x| {
-				x.changeset = changeset_id;
-				x.version += 1;
-			});
+			modify.node.iter_mut().for_each(|x| x.changeset = changeset_id);
 			modify.way.iter_mut().for_each(|x| x.changeset = changeset_id);
 		}
+
+		if let Some(delete) = self.delete.as_mut() {
+			delete.node.iter_mut().for_each(|x| x.changeset = changeset_id);
+			delete.way.iter_mut().for_each(|x| x.changeset = changeset_id);
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.create.as_ref().is_none_or(Create::is_empty)
+			&& self.modify.as_ref().is_none_or(Modify::is_empty)
+			&& self.delete.as_ref().is_none_or(Delete::is_empty)
+	}
+}
+
+// Response of the upload endpoint, maps the uploaded IDs to their new IDs and versions.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DiffResult {
+	#[serde(rename = "$value", default)]
+	pub entries: Vec<DiffEntry>,
+}
+
+impl DiffResult {
+	pub fn parse(text: &str) -> Result<Self, DeError> {
+		from_str(text)
+	}
+}
+
+// Elements are listed in the order of the uploaded osmChange, so they are deserialized as a single list.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffEntry {
+	Node(DiffElement),
+	Way(DiffElement),
+	Relation(DiffElement),
+}
+
+// new_id and new_version are missing for deleted elements.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiffElement {
+	#[serde(rename = "@old_id")]
+	pub old_id: Id,
+	#[serde(rename = "@new_id")]
+	pub new_id: Option<Id>,
+	#[serde(rename = "@new_version")]
+	pub new_version: Option<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use osm_parser::{Coordinate, Tags};
+
+	fn tags() -> Tags {
+		let mut tags = Tags::default();
+		tags.insert("highway".into(), "residential".into());
+		tags.insert("name".into(), "Schulstraße & <Bahnhof>".into());
+		tags
+	}
+
+	fn node(id: osm_parser::Id) -> osm_parser::Node {
+		osm_parser::Node {
+			id,
+			pos: Coordinate::new(50.059_561_3, 10.216_837_9),
+			tags: tags(),
+			version: 3,
+			changeset: 12_345,
+		}
+	}
+
+	fn way(id: osm_parser::Id, nodes: Vec<osm_parser::Id>) -> osm_parser::Way {
+		osm_parser::Way {
+			id,
+			nodes,
+			tags: tags(),
+			version: 7,
+			changeset: 12_345,
+		}
+	}
+
+	fn assert_tags_eq(tags: &[Tag], expected: &Tags) {
+		assert_eq!(tags.len(), expected.len());
+		for tag in tags {
+			assert_eq!(expected.get(&tag.k), Some(&tag.v));
+		}
+	}
+
+	#[allow(clippy::float_cmp)]
+	fn assert_node_eq(node: &Node, expected: &osm_parser::Node) {
+		assert_eq!(from_osmchange_id(node.id), expected.id);
+		assert_eq!(node.version, expected.version);
+		assert_eq!(node.changeset, expected.changeset);
+		assert_eq!(node.lat, expected.pos.lat);
+		assert_eq!(node.lon, expected.pos.lon);
+		assert_tags_eq(&node.tags, &expected.tags);
+	}
+
+	fn assert_way_eq(way: &Way, expected: &osm_parser::Way) {
+		assert_eq!(from_osmchange_id(way.id), expected.id);
+		assert_eq!(way.version, expected.version);
+		assert_eq!(way.changeset, expected.changeset);
+		assert_eq!(way.nodes.iter().map(|nd| from_osmchange_id(nd.r#ref)).collect::<Vec<_>>(), expected.nodes);
+		assert_tags_eq(&way.tags, &expected.tags);
+	}
+
+	fn round_trip(osmchange: &OsmChange) -> OsmChange {
+		let text = osmchange.to_string_pretty().unwrap();
+		from_str(&text).unwrap()
+	}
+
+	#[test]
+	fn placeholder_ids() {
+		assert_eq!(to_osmchange_id(42), 42);
+		assert_eq!(to_osmchange_id(osm_parser::Id::MAX), -1);
+		assert_eq!(to_osmchange_id(osm_parser::Id::MAX - 1), -2);
+		assert_eq!(from_osmchange_id(-2), osm_parser::Id::MAX - 1);
+		assert!(is_placeholder_id(osm_parser::Id::MAX));
+		assert!(!is_placeholder_id(42));
+	}
+
+	#[test]
+	fn modify_round_trip() {
+		let bench = node(3);
+		let mut updated = node(3);
+		updated.tags.insert("amenity".into(), "bench".into());
+		let node = node(1);
+		let prev = way(2, vec![1, 3, 4]);
+		let way = way(2, vec![1, 3, 4, 1]);
+		let changes = vec![
+			Change::UpdateWay(prev, way.clone()),
+			Change::UpdateNode(bench, updated.clone()),
+			Change::DeleteNode(node.clone()),
+		];
+
+		let osmchange = round_trip(&OsmChange::from(&changes));
+		assert!(osmchange.create.is_none());
+
+		let modify = osmchange.modify.expect("modify block missing");
+		assert_eq!(modify.node.len(), 1);
+		assert_node_eq(&modify.node[0], &updated);
+		assert_eq!(modify.way.len(), 1);
+		assert_way_eq(&modify.way[0], &way);
+
+		let delete = osmchange.delete.expect("delete block missing");
+		assert_eq!(delete.if_unused, Some(true));
+		assert!(delete.way.is_empty());
+		assert_eq!(delete.node.len(), 1);
+		assert_node_eq(&delete.node[0], &node);
+	}
+
+	#[test]
+	fn create_round_trip() {
+		let first = node(osm_parser::Id::MAX);
+		let second = node(osm_parser::Id::MAX - 1);
+		let way = way(osm_parser::Id::MAX - 2, vec![first.id, second.id, 5]);
+		let changes = vec![
+			Change::CreateNode(first.clone()),
+			Change::CreateNode(second.clone()),
+			Change::CreateWay(way.clone()),
+		];
+
+		let osmchange = OsmChange::from(&changes);
+		let text = osmchange.to_string_pretty().unwrap();
+		assert!(text.contains(r#"<node id="-1""#));
+		assert!(text.contains(r#"<nd ref="-2"/>"#));
+
+		let create = round_trip(&osmchange).create.expect("create block missing");
+		assert_eq!(create.node.len(), 2);
+		assert_node_eq(&create.node[0], &first);
+		assert_node_eq(&create.node[1], &second);
+		assert_eq!(create.way.len(), 1);
+		assert_way_eq(&create.way[0], &way);
+	}
+
+	#[test]
+	fn created_and_deleted_is_discarded() {
+		let node = node(osm_parser::Id::MAX);
+		let changes = vec![
+			Change::CreateNode(node.clone()),
+			Change::DeleteNode(node),
+		];
+
+		assert!(OsmChange::from(&changes).is_empty());
 	}
 }
@@ -16,6 +16,18 @@ pub enum Provider {
 	Bavaria20cm,
 }
 
+impl Provider {
+	// Name used in the imagery_used changeset tag.
+	pub const fn imagery_name(self) -> &'static str {
+		match self {
+			Self::OpenStreetMap => "OpenStreetMap Carto",
+			Self::MapboxSatellite => "Mapbox Satellite",
+			Self::EsriWorldImagery => "Esri World Imagery",
+			Self::Bavaria20cm => "Bayerische Vermessungsverwaltung DOP20",
+		}
+	}
+}
+
 pub enum TilesKind {
 	Http(HttpTiles),
 }
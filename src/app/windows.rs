@@ -1,15 +1,17 @@
 use super::editor::{
 	cache::{Change, ElementRef},
 	consts::{osm::*, *},
-	states::{MapDownloadState, MapState, SelectionFlag},
+	merge::{Conflict, Side},
+	states::{ChangesetForm, MapDownloadState, MapState, SelectionFlag, ServerForm, TagEditorState, Tool},
 	visual::{FillMode, Visualization},
 };
 use super::icons;
-use super::osm::Bbox;
+use super::osm::{Bbox, Tile};
 use super::providers::Provider;
 use eframe::egui;
 use egui::text::LayoutJob;
-use egui::{Align2, Area, AtomExt, Button, Color32, CornerRadius, Event, FontId, Frame, Grid, Image, ImageSource, InnerResponse, Key, Margin, Order, Pos2, Shadow, Stroke, TextFormat, Ui, Vec2};
+use std::fmt::Write;
+use egui::{Align2, Area, AtomExt, Button, Color32, CornerRadius, Event, FontId, Frame, Grid, Image, ImageSource, InnerResponse, Key, KeyboardShortcut, Margin, Order, Pos2, Shadow, Stroke, TextFormat, Ui, Vec2};
 use walkers::sources::Attribution;
 
 const TRANSPARENT_FRAME: Frame = Frame {
@@ -87,21 +89,79 @@ pub fn acknowledge(ui: &Ui, attribution: Attribution, simple: bool) {
 		});
 }
 
-pub fn tags(ui: &Ui, tags: &osm_parser::Tags) {
+// Shows the tags of the element, they can be edited if an editor is given.
+// Returns the new tags once an edit is done.
+pub fn tags(ui: &Ui, element: &ElementRef, editor: Option<&mut TagEditorState>) -> Option<osm_parser::Tags> {
 	egui::Window::new("Tags")
 		.collapsible(true)
 		.resizable(false)
 		.anchor(Align2::LEFT_TOP, [WINDOW_MARGIN, TOP_BAR_HEIGHT + WINDOW_MARGIN + 54.]) // todo: extract magic number
 		.frame(TRANSPARENT_FRAME)
 		.show(ui.ctx(), |ui| {
-			Grid::new("tags").show(ui, |ui| {
-				for (k, v) in tags {
-					ui.label(k);
-					ui.label(v);
-					ui.end_row();
-				}
+			let Some(state) = editor else {
+				Grid::new("tags").show(ui, |ui| {
+					for (k, v) in element.tags() {
+						ui.label(k);
+						ui.label(v);
+						ui.end_row();
+					}
+				});
+				return None;
+			};
+
+			state.load(&element.element_id(), element.tags());
+
+			ui.horizontal(|ui| {
+				if ui.selectable_label(!state.text_view, "Table").clicked() { state.switch_view(false); }
+				if ui.selectable_label(state.text_view, "Text").clicked() { state.switch_view(true); }
 			});
-		});
+
+			let tags = if state.text_view {
+				let resp = ui.add(egui::TextEdit::multiline(&mut state.text)
+					.code_editor()
+					.desired_rows(8)
+					.hint_text("key=value"));
+				for (k, v) in state.text.lines().filter_map(|x| x.split_once('=')) {
+					length_warning(ui, k.trim());
+					length_warning(ui, v.trim());
+				}
+
+				(resp.lost_focus() && state.is_valid()).then(|| state.edited_tags())
+			} else {
+				let mut edited = false;
+				let mut removed = None;
+
+				Grid::new("tags").num_columns(3).show(ui, |ui| {
+					for (i, (k, v)) in state.rows.iter_mut().enumerate() {
+						ui.vertical(|ui| {
+							edited |= ui.add(egui::TextEdit::singleline(k).desired_width(120.0).hint_text("key")).lost_focus();
+							length_warning(ui, k.trim());
+						});
+						ui.vertical(|ui| {
+							edited |= ui.add(egui::TextEdit::singleline(v).desired_width(160.0).hint_text("value")).lost_focus();
+							length_warning(ui, v.trim());
+						});
+						if ui.small_button("🗑").on_hover_text("Remove tag").clicked() {
+							removed = Some(i);
+						}
+						ui.end_row();
+					}
+				});
+
+				if let Some(i) = removed {
+					state.rows.remove(i);
+					edited = true;
+				}
+
+				if ui.button("Add tag").clicked() {
+					state.rows.push((String::new(), String::new()));
+				}
+
+				(edited && state.is_valid()).then(|| state.edited_tags())
+			};
+
+			tags.filter(|x| x != element.tags())
+		}).and_then(|x| x.inner).flatten()
 }
 
 // Returns whether the licenses button was pressed
@@ -151,32 +211,68 @@ pub fn map<'a>(
 
 				ui.add(egui::Slider::new(&mut map_state.scale_factor, 0.1..=2.0).text("Scale factor"));
 				ui.checkbox(&mut map_state.zoom_with_ctrl, "Zoom with Ctrl");
+				ui.checkbox(&mut map_state.auto_download, "Download automatically")
+					.on_hover_text(format!("Download missing data while panning, from zoom level {AUTO_DOWNLOAD_MIN_ZOOM} onwards"));
 
 				ui.button("Show Open-Source Licenses").clicked()
 			}).body_returned.unwrap_or(false)
 		}).unwrap().inner.unwrap_or(false)
 }
 
-pub fn history(ui: &Ui, history: &Vec<Change>) {
+pub enum HistoryResult {
+	None,
+	Undo,
+	Redo,
+}
+
+pub fn history(ui: &Ui, changes: &[Change], undone: &[Change], conflicts: usize) -> HistoryResult {
 	egui::Window::new("History")
 		.max_height(256.0)
 		.anchor(Align2::RIGHT_TOP, [-10., 42.])
 		.frame(TRANSPARENT_FRAME)
 		.show(ui.ctx(), |ui| {
-			if history.is_empty() {
+			let mut result = HistoryResult::None;
+
+			if conflicts > 0 {
+				ui.colored_label(ui.visuals().warn_fg_color, format!("{conflicts} conflict(s), merge them in the Upload tab"));
+			}
+
+			ui.horizontal(|ui| {
+				let undo = ui.add_enabled(!changes.is_empty(), Button::new("Undo"))
+					.on_hover_text(ui.ctx().format_shortcut(shortcuts::UNDO));
+				if undo.clicked() { result = HistoryResult::Undo; }
+
+				let redo = ui.add_enabled(!undone.is_empty(), Button::new("Redo"))
+					.on_hover_text(ui.ctx().format_shortcut(shortcuts::REDO));
+				if redo.clicked() { result = HistoryResult::Redo; }
+			});
+
+			if changes.is_empty() && undone.is_empty() {
 				ui.weak("Empty");
 			} else {
 				egui::ScrollArea::vertical().auto_shrink([true, false]).show(ui, |ui| {
-					for change in history {
+					for change in changes {
 						ui.label(format!("{change}"));
 					}
+
+					// in the order they are redone
+					for change in undone.iter().rev() {
+						ui.weak(format!("{change}"));
+					}
 				});
 			}
-		});
+
+			result
+		}).and_then(|x| x.inner).unwrap_or(HistoryResult::None)
+}
+
+pub enum ToolbarResult {
+	None,
+	Download,
+	CancelDownload,
 }
 
-// Returns whether a download was triggered
-pub fn toolbar(ui: &Ui, state: &mut MapState, bbox: &Bbox) -> bool {
+pub fn toolbar(ui: &Ui, state: &mut MapState, bbox: &Bbox) -> ToolbarResult {
 	egui::Window::new("Toolbar")
 		.title_bar(false)
 		.resizable(false)
@@ -209,33 +305,95 @@ pub fn toolbar(ui: &Ui, state: &mut MapState, bbox: &Bbox) -> bool {
 
 				ui.separator();
 
+				/* tools */ {
+					let tools: [(Tool, ImageSource, &KeyboardShortcut, &str); 2] = [
+						(Tool::AddPoi, icons::POI, shortcuts::ADD_POI, "Add point"),
+						(Tool::DrawWay, icons::DRAW, shortcuts::DRAW_WAY, "Draw line or area, double-click to finish"),
+					];
+
+					for (tool, icon, shortcut, hover) in tools {
+						let active = state.tool == tool;
+						let image = Image::new(icon).fit_to_exact_size(Vec2::splat(24.0));
+
+						let resp = ui.add(Button::image(image).selected(active).corner_radius(4)).on_hover_text(hover);
+						if resp.clicked() || (!ui.ctx().wants_keyboard_input() && ui.input_mut(|i| i.consume_shortcut(shortcut))) {
+							state.tool = if active { Tool::Select } else { tool };
+						}
+					}
+
+					let presets = match state.tool {
+						Tool::Select => None,
+						Tool::AddPoi => Some((POI_PRESETS.as_slice(), &mut state.poi_preset)),
+						Tool::DrawWay => Some((WAY_PRESETS.as_slice(), &mut state.way_preset)),
+					};
+
+					if let Some((presets, selected)) = presets {
+						egui::ComboBox::from_id_salt("tool_preset")
+							.selected_text(presets[*selected].0)
+							.show_ui(ui, |ui| {
+								for (i, (name, ..)) in presets.iter().enumerate() {
+									ui.selectable_value(selected, i, *name);
+								}
+							});
+					}
+
+					if state.tool != Tool::Select && !ui.ctx().wants_keyboard_input() && ui.input_mut(|i| i.consume_shortcut(shortcuts::CANCEL_TOOL)) {
+						state.tool = Tool::Select;
+					}
+				}
+
+				ui.separator();
+
 				/* map download */ {
 					match &state.download {
 						MapDownloadState::Idle(status) => {
-							let enabled = bbox.area() < MAX_DOWNLOAD_AREA;
+							let enabled = Tile::count(bbox) <= MAX_DOWNLOAD_TILES;
 							let time = ui.ctx().input(|i| i.time);
 
 							let button_resp = if let Some((status, prev_time)) = status && time - prev_time < DOWNLOAD_FEEDBACK_SECONDS {
 								let text = egui::RichText::new(if status.is_ok() { "✔" } else { "✘" }).strong();
-								ui.add_enabled(enabled, Button::new(text).min_size(Vec2::splat(TOP_BAR_BUTTON_SIZE)).corner_radius(4))
+								let resp = ui.add_enabled(enabled, Button::new(text).min_size(Vec2::splat(TOP_BAR_BUTTON_SIZE)).corner_radius(4));
+								if let Err(err) = status { resp.on_hover_text(err.to_string()) } else { resp }
 							} else { // todo: global error modal / success toast
 								let image = Image::new(icons::DOWNLOAD).fit_to_exact_size(Vec2::splat(TOP_BAR_BUTTON_SIZE - 4.0));
 								ui.add_enabled(enabled, Button::image(image).corner_radius(4))
 							};
 
-							// Return whether a download was triggered
-							enabled && !ui.ctx().wants_keyboard_input() && (button_resp.clicked() || ui.input_mut(|i| {
+							let download = enabled && !ui.ctx().wants_keyboard_input() && (button_resp.clicked() || ui.input_mut(|i| {
 								let any_echo_events = i.events.iter().any(|e| {
 									if let Event::Key { repeat, .. } = e { *repeat } else { false }
 								});
 								!any_echo_events && i.consume_shortcut(shortcuts::DOWNLOAD)
-							}))
+							}));
+
+							if download { ToolbarResult::Download } else { ToolbarResult::None }
 						}
-						MapDownloadState::Downloading => {
-							let resp = ui.add_enabled(false, Button::new(()).min_size(Vec2::splat(TOP_BAR_BUTTON_SIZE)));
-							ui.put(resp.rect, egui::Spinner::new());
+						MapDownloadState::Downloading(progress) => {
+							let time = ui.ctx().input(|i| i.time);
+
+							let (text, hover) = if let Some((err, retry_time)) = &progress.retry {
+								let remaining = (retry_time - time).max(0.0);
+								(format!("{}s", remaining.ceil()), format!("Retrying download, previous attempt failed:\n{err}"))
+							} else {
+								let text = progress.fraction().map_or_else(String::new, |x| format!("{:.0}%", x * 100.0));
+								let mut hover = format!("Downloading {} of {} tiles", progress.tiles, progress.total_tiles);
+								if let Some(current) = progress.current {
+									let _ = write!(hover, "\n{current}");
+								}
+								(text, hover)
+							};
+
+							let resp = ui.add_enabled(!progress.cancelled, Button::new(egui::RichText::new(text).strong()).min_size(Vec2::splat(TOP_BAR_BUTTON_SIZE)).corner_radius(4));
+							if progress.retry.is_none() && progress.fraction().is_none() {
+								ui.put(resp.rect, egui::Spinner::new());
+							}
 
-							false
+							let cancel = resp.on_hover_text(format!("{hover}\nClick to cancel")).on_disabled_hover_text("Cancelling…").clicked();
+
+							// progress is received from the worker, and the countdown has to be kept up to date
+							ui.ctx().request_repaint_after_secs(0.2);
+
+							if cancel { ToolbarResult::CancelDownload } else { ToolbarResult::None }
 						}
 					}
 				}
@@ -243,6 +401,91 @@ pub fn toolbar(ui: &Ui, state: &mut MapState, bbox: &Bbox) -> bool {
 		}).unwrap().inner.unwrap()
 }
 
+fn length_warning(ui: &mut Ui, text: &str) {
+	let length = text.chars().count();
+	if length > MAX_TAG_VALUE_LENGTH {
+		ui.colored_label(ui.visuals().error_fg_color, format!("Too long ({length}/{MAX_TAG_VALUE_LENGTH} characters)"));
+	}
+}
+
+pub fn changeset_form(ui: &mut Ui, form: &mut ChangesetForm, used_providers: &[Provider]) {
+	Grid::new("changeset_form").num_columns(2).show(ui, |ui| {
+		ui.label("Comment");
+		ui.vertical(|ui| {
+			ui.add(egui::TextEdit::multiline(&mut form.comment)
+				.hint_text("Briefly describe your changes")
+				.desired_rows(2));
+			if form.is_comment_empty() {
+				ui.colored_label(ui.visuals().warn_fg_color, "A comment is required.");
+			}
+			length_warning(ui, &form.comment);
+		});
+		ui.end_row();
+
+		ui.label("Source");
+		ui.vertical(|ui| {
+			ui.add(egui::TextEdit::singleline(&mut form.source).hint_text("survey;local knowledge"));
+			length_warning(ui, &form.source);
+		});
+		ui.end_row();
+
+		ui.label("Hashtags");
+		ui.vertical(|ui| {
+			ui.add(egui::TextEdit::singleline(&mut form.hashtags).hint_text("#hashtag"));
+			length_warning(ui, &form.hashtags());
+		});
+		ui.end_row();
+
+		ui.label("Imagery");
+		if used_providers.is_empty() {
+			ui.weak("None");
+		} else {
+			ui.label(used_providers.iter().map(|x| x.imagery_name()).collect::<Vec<_>>().join(", "));
+		}
+		ui.end_row();
+	});
+}
+
+// Returns whether the merge result should be applied
+pub fn merge_conflict(ui: &mut Ui, conflict: &mut Conflict) -> bool {
+	let server = conflict.server.element_ref();
+	ui.strong(format!("Conflict in {} {}", server.type_str(), server.id_ref()));
+	ui.label(format!("This element was changed on the server (version {}) since it was downloaded. Choose which changes to keep:", conflict.server.version()));
+
+	if let Some(choice) = &mut conflict.deletion {
+		ui.horizontal(|ui| {
+			ui.label("Deleted locally, but modified on the server:");
+			ui.selectable_value(choice, Side::Local, "Delete");
+			ui.selectable_value(choice, Side::Server, "Keep");
+		});
+	}
+
+	if !conflict.tags.is_empty() || conflict.geometry.is_some() {
+		Grid::new("merge_conflict").striped(true).show(ui, |ui| {
+			ui.strong("Key");
+			ui.strong("Local");
+			ui.strong("Server");
+			ui.end_row();
+
+			for tag in &mut conflict.tags {
+				ui.label(&tag.key);
+				ui.selectable_value(&mut tag.choice, Side::Local, tag.local.as_deref().unwrap_or("(removed)"));
+				ui.selectable_value(&mut tag.choice, Side::Server, tag.server.as_deref().unwrap_or("(removed)"));
+				ui.end_row();
+			}
+
+			if let Some(choice) = &mut conflict.geometry {
+				ui.label("Geometry");
+				ui.selectable_value(choice, Side::Local, "Local");
+				ui.selectable_value(choice, Side::Server, "Server");
+				ui.end_row();
+			}
+		});
+	}
+
+	ui.button("Apply").clicked()
+}
+
 #[cfg(feature = "debug")]
 use crate::app::editor::{cache::EditorOsmData, states::CacheFlag};
 
@@ -394,3 +637,27 @@ pub fn overlap_selector<'a>(ui: &Ui, pos: Pos2, hovered: Vec<ElementRef<'a>>) ->
 			resp
 		}).unwrap()
 }
+
+// Returns whether a valid server was submitted.
+pub fn server_form(ui: &mut Ui, form: &mut ServerForm) -> bool {
+	Grid::new("server_form").num_columns(2).show(ui, |ui| {
+		ui.label("Name");
+		ui.text_edit_singleline(&mut form.name);
+		ui.end_row();
+
+		ui.label("URL");
+		ui.add(egui::TextEdit::singleline(&mut form.url).hint_text("https://example.org or http://localhost:3000"));
+		ui.end_row();
+
+		ui.label("OAuth 2 client ID");
+		ui.text_edit_singleline(&mut form.client_id);
+		ui.end_row();
+	});
+
+	let server = form.target_server();
+	if let Some(server) = &server && !server.https {
+		ui.colored_label(ui.visuals().warn_fg_color, "Tokens are sent unencrypted over http, only use this for local servers.");
+	}
+
+	ui.add_enabled(server.is_some(), Button::new("Add")).clicked()
+}
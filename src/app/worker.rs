@@ -1,11 +1,17 @@
-use super::osm::{Bbox, OsmClient, OsmResult, OsmToken, TargetServer};
-use super::osmchange::Tag;
+use super::osm::{Account, AuthCode, MapProgress, OsmApiError, OsmClient, OsmResult, TargetServer, Tile, UserDetails, VersionConflict};
+use super::osmchange::{DiffResult, OsmChange, Tag};
 use osm_parser::OsmData;
+use std::collections::HashMap;
 use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 #[cfg(not(target_family = "wasm"))]
 use {
+	super::osm::{PendingAuthorization, AUTHORIZATION_TIMEOUT},
 	crossbeam_channel::{Receiver, Sender},
+	std::collections::VecDeque,
 	std::thread::JoinHandle,
 };
 
@@ -15,34 +21,124 @@ use futures::{
 	stream::StreamExt,
 };
 
+#[cfg(any(target_family = "wasm", test))]
+mod message;
+#[cfg(target_family = "wasm")]
+mod web;
+
+const MAX_CONCURRENT_DOWNLOADS: usize = 4;
+
+// Assigned by the WorkerHandle, all responses to a request carry its id.
+pub type RequestId = u64;
+
+// Set by Request::Cancel, checked by the running request between its steps.
+#[derive(Debug, Default, Clone)]
+struct CancelFlag(Arc<AtomicBool>);
+
+impl CancelFlag {
+	fn cancel(&self) {
+		self.0.store(true, Ordering::Relaxed);
+	}
+
+	fn is_cancelled(&self) -> bool {
+		self.0.load(Ordering::Relaxed)
+	}
+}
+
+// Serialized only when crossing the boundary of the Web Worker, see message.rs.
+#[derive(Debug)]
+#[cfg_attr(any(target_family = "wasm", test), derive(serde::Serialize, serde::Deserialize))]
 pub enum Request {
-	GetMap(Box<Bbox>), // box is used to keep enum size small
+	/// Downloads the tiles concurrently, answered with a MapTile for each of them and a final Map.
+	GetMap(Vec<Tile>),
+	/// Stops a running request as soon as possible, its final response contains OsmApiError::Cancelled.
+	Cancel(RequestId),
 	SetTargetServer(TargetServer),
-	FetchToken(String),
-	CreateChangeset(Vec<Tag>),
-	#[allow(dead_code)]
-	CloseChangeset(NonZeroU32),
+	/// Starts the PKCE flow with a loopback redirect, answered with AuthorizeUrl and later AuthCode.
+	#[cfg(not(target_family = "wasm"))]
+	Authorize,
+	/// Fetches the token and its user, which is added as the active account of the target server.
+	FetchToken(AuthCode),
+	/// Fetches the users of all accounts of the target server, which also validates their tokens.
+	ValidateAccounts,
+	/// Selects the account (by user id) used for all authenticated requests to the target server.
+	SetActiveAccount(u64),
+	/// Revokes the token of the account (by user id) and forgets it.
+	Logout(u64),
+	/// Creates a changeset, uploads the osmChange to it and closes it again.
+	UploadChangeset(Vec<Tag>, Box<OsmChange>),
+}
+
+impl Request {
+	// Requests that only change the state of the worker are handled right away and in order, all others run concurrently.
+	const fn is_state_change(&self) -> bool {
+		matches!(self, Self::Cancel(_) | Self::SetTargetServer(_) | Self::SetActiveAccount(_))
+	}
 }
 
 #[derive(Debug)]
+#[cfg_attr(any(target_family = "wasm", test), derive(serde::Serialize, serde::Deserialize))]
 pub enum Response {
-	Map(OsmResult<OsmData>),
-	Token(OsmResult<OsmToken>, TargetServer),
+	MapTile(Tile, #[cfg_attr(any(target_family = "wasm", test), serde(with = "message::osm_data"))] OsmResult<OsmData>), // tiles that failed can be requested again
+	MapRetry(OsmApiError, Duration), // the download of a tile failed and is retried after the duration
+	MapProgress(MapProgress), // of a tile in flight
+	Map(OsmResult<()>), // all tiles were downloaded, or the first error
+
+	#[cfg(not(target_family = "wasm"))]
+	AuthorizeUrl(String), // to be opened in the browser
+	#[cfg(not(target_family = "wasm"))]
+	AuthCode(OsmResult<AuthCode>),
+	Account(OsmResult<Account>, TargetServer),
+	AccountValidated(u64, OsmResult<UserDetails>, TargetServer), // accounts with expired tokens are removed
+	LoggedOut(OsmResult<()>, TargetServer),
 	CreatedChangeset(OsmResult<NonZeroU32>),
+	UploadedChangeset(OsmResult<DiffResult>),
+	Conflict(VersionConflict, #[cfg_attr(any(target_family = "wasm", test), serde(with = "message::osm_data"))] OsmResult<OsmData>), // current server version of the conflicting element
 	ClosedChangeset(OsmResult<NonZeroU32>),
 }
 
+// Shared by all running requests, each of them works on a copy of the client taken when it starts.
+#[derive(Clone)]
 pub struct Worker {
-	pub osm_client: OsmClient,
-	pub sender: Sender<Response>,
+	osm_client: Arc<Mutex<OsmClient>>,
+	sender: Sender<(RequestId, Response)>,
+	running: Arc<Mutex<HashMap<RequestId, CancelFlag>>>,
 }
 
 impl Worker {
-	pub fn send_message(&self, msg: Response) {
+	pub fn new(osm_client: OsmClient, sender: Sender<(RequestId, Response)>) -> Self {
+		Self {
+			osm_client: Arc::new(Mutex::new(osm_client)),
+			sender,
+			running: Arc::default(),
+		}
+	}
+
+	pub fn send_message(&self, id: RequestId, msg: Response) {
 		#[cfg(not(target_family = "wasm"))]
-		self.sender.send(msg).unwrap();
+		self.sender.send((id, msg)).unwrap();
 		#[cfg(target_family = "wasm")]
-		self.sender.unbounded_send(msg).unwrap();
+		self.sender.unbounded_send((id, msg)).unwrap();
+	}
+
+	fn client(&self) -> OsmClient {
+		self.osm_client.lock().unwrap().clone()
+	}
+
+	fn start(&self, id: RequestId) -> CancelFlag {
+		let cancel = CancelFlag::default();
+		self.running.lock().unwrap().insert(id, cancel.clone());
+		cancel
+	}
+
+	fn finish(&self, id: RequestId) {
+		self.running.lock().unwrap().remove(&id);
+	}
+
+	fn cancel(&self, id: RequestId) {
+		if let Some(cancel) = self.running.lock().unwrap().get(&id) {
+			cancel.cancel();
+		}
 	}
 }
 
@@ -50,26 +146,54 @@ pub struct WorkerHandle {
 	#[cfg(not(target_family = "wasm"))]
 	#[allow(dead_code)]
 	pub thread: JoinHandle<()>,
-	pub sender: Sender<Request>,
-	pub receiver: Receiver<Response>,
+	pub sender: Sender<(RequestId, Request)>,
+	pub receiver: Receiver<(RequestId, Response)>,
+	pub next_id: RequestId,
 }
 
 impl WorkerHandle {
-	pub fn send_message(&self, msg: Request) {
+	// Runs the worker on its own thread.
+	#[cfg(not(target_family = "wasm"))]
+	pub fn spawn(osm_client: OsmClient) -> Self {
+		let (request_sender, request_receiver) = crossbeam_channel::unbounded();
+		let (response_sender, response_receiver) = crossbeam_channel::unbounded();
+		let worker = Worker::new(osm_client, response_sender);
+
+		Self {
+			thread: std::thread::spawn(move || worker.run(request_receiver)),
+			sender: request_sender,
+			receiver: response_receiver,
+			next_id: 0,
+		}
+	}
+
+	// Runs the worker in a Web Worker, so decoding large responses does not block the UI.
+	#[cfg(target_family = "wasm")]
+	pub fn spawn(osm_client: OsmClient) -> Self {
+		web::spawn(&osm_client)
+	}
+
+	// Returns the id of the request, which is echoed by its responses.
+	pub fn send_message(&mut self, msg: Request) -> RequestId {
+		let id = self.next_id;
+		self.next_id += 1;
+
 		#[cfg(not(target_family = "wasm"))]
-		self.sender.send(msg).unwrap();
+		self.sender.send((id, msg)).unwrap();
 		#[cfg(target_family = "wasm")]
-		self.sender.unbounded_send(msg).unwrap();
+		self.sender.unbounded_send((id, msg)).unwrap();
+
+		id
 	}
 
 	/// Returns all received messages without blocking.
 	#[cfg(not(target_family = "wasm"))]
-	pub fn recv_messages(&self) -> Vec<Response> {
+	pub fn recv_messages(&self) -> Vec<(RequestId, Response)> {
 		self.receiver.try_iter().collect::<Vec<_>>()
 	}
 
 	#[cfg(target_family = "wasm")]
-	pub fn recv_messages(&mut self) -> Vec<Response> {
+	pub fn recv_messages(&mut self) -> Vec<(RequestId, Response)> {
 		let mut messages = vec![];
 		while let Ok(msg) = self.receiver.try_next() {
 			if let Some(msg) = msg {
@@ -80,90 +204,483 @@ impl WorkerHandle {
 	}
 }
 
+fn download_result(error: Option<OsmApiError>, cancel: &CancelFlag) -> OsmResult<()> {
+	match error {
+		Some(err) => Err(err),
+		None if cancel.is_cancelled() => Err(OsmApiError::Cancelled),
+		None => Ok(()),
+	}
+}
+
+fn accounts(client: &OsmClient) -> Vec<Account> {
+	client.accounts.get(&client.target_server.base_url())
+		.map(|x| x.list.clone())
+		.unwrap_or_default()
+}
+
+// accounts of the worker, shared by the native and web message handlers
+// the server is the target server of the request, which may have been changed while it was running
+impl Worker {
+	fn add_account(&self, id: RequestId, server: TargetServer, account: OsmResult<Account>) {
+		if let Ok(account) = &account {
+			self.osm_client.lock().unwrap().accounts.entry(server.base_url()).or_default().insert(account.clone());
+		}
+		self.send_message(id, Response::Account(account, server));
+	}
+
+	fn account_validated(&self, id: RequestId, server: &TargetServer, user_id: u64, result: OsmResult<UserDetails>) {
+		if let Some(accounts) = self.osm_client.lock().unwrap().accounts.get_mut(&server.base_url()) {
+			match &result {
+				Ok(user) => accounts.update_user(user.clone()),
+				Err(OsmApiError::AuthExpired) => { accounts.remove(user_id); }
+				Err(_) => {}
+			}
+		}
+		self.send_message(id, Response::AccountValidated(user_id, result, server.clone()));
+	}
+
+	fn set_active_account(&self, user_id: u64) {
+		let mut client = self.osm_client.lock().unwrap();
+		let base_url = client.target_server.base_url();
+		if let Some(accounts) = client.accounts.get_mut(&base_url) {
+			accounts.set_active(user_id);
+		}
+	}
+
+	fn remove_account(&self, server: &TargetServer, user_id: u64) -> Option<Account> {
+		self.osm_client.lock().unwrap().accounts.get_mut(&server.base_url())?.remove(user_id)
+	}
+}
+
 impl Worker {
 	#[cfg(target_family = "wasm")]
 	#[allow(clippy::future_not_send)]
-	async fn handle_message(&mut self, request: Request) {
+	async fn handle_message(&self, id: RequestId, request: Request, cancel: &CancelFlag) {
+		let client = self.client();
 		match request {
-			Request::GetMap(bbox) => {
-				let data = self.osm_client.get_map(&bbox);
-				#[cfg(target_family = "wasm")] let data = data.await;
+			Request::GetMap(tiles) => {
+				let mut error = None;
 
-				self.send_message(Response::Map(data));
+				// the browser runs the requests concurrently
+				let mut downloads = futures::stream::iter(tiles)
+					.map(|tile| {
+						let client = &client;
+						async move {
+							if cancel.is_cancelled() { return None; }
+							let result = client.get_tile(tile,
+								|err, delay| self.send_message(id, Response::MapRetry(err.clone(), delay)),
+								|progress| self.send_message(id, Response::MapProgress(progress)),
+							).await;
+							Some((tile, result))
+						}
+					})
+					.buffer_unordered(MAX_CONCURRENT_DOWNLOADS);
+
+				while let Some(download) = downloads.next().await {
+					let Some((tile, result)) = download else { continue; };
+					if let Err(err) = &result {
+						error.get_or_insert_with(|| err.clone());
+					}
+					self.send_message(id, Response::MapTile(tile, result));
+				}
+
+				self.send_message(id, Response::Map(download_result(error, cancel)));
+			}
+			Request::Cancel(target) => {
+				self.cancel(target);
 			}
 			Request::SetTargetServer(target) => {
-				self.osm_client.target_server = target;
+				self.osm_client.lock().unwrap().set_target_server(target);
 			}
 			Request::FetchToken(auth_code) => {
-				let token = self.osm_client.fetch_token(auth_code);
-				#[cfg(target_family = "wasm")] let token = token.await;
-
-				let target_server = self.osm_client.target_server;
-
-				if let Ok(token) = token.as_ref() {
-					self.osm_client.auth_token[target_server as usize] = Some(token.to_owned());
+				let account = match client.fetch_token(&auth_code).await {
+					Ok(token) => client.get_user_details(&token).await.map(|user| Account { user, token }),
+					Err(err) => Err(err),
+				};
+				self.add_account(id, client.target_server, account);
+			}
+			Request::ValidateAccounts => {
+				for account in accounts(&client) {
+					if cancel.is_cancelled() { break; }
+					let result = client.get_user_details(&account.token).await;
+					self.account_validated(id, &client.target_server, account.user.id, result);
 				}
-
-				self.send_message(Response::Token(token, target_server));
 			}
-			Request::CreateChangeset(tags) => {
-				let result = self.osm_client.create_changeset(tags);
-				#[cfg(target_family = "wasm")] let result = result.await;
-
-				self.send_message(Response::CreatedChangeset(result));
+			Request::SetActiveAccount(user_id) => {
+				self.set_active_account(user_id);
+			}
+			Request::Logout(user_id) => {
+				let result = match self.remove_account(&client.target_server, user_id) {
+					Some(account) => client.revoke_token(&account.token).await,
+					None => Ok(()),
+				};
+				self.send_message(id, Response::LoggedOut(result, client.target_server));
 			}
-			Request::CloseChangeset(id) => {
-				let result = self.osm_client.close_changeset(id);
-				#[cfg(target_family = "wasm")] let result = result.await;
+			Request::UploadChangeset(tags, mut osmchange) => {
+				let result = client.create_changeset(tags).await;
+				let changeset = result.as_ref().ok().copied();
+				self.send_message(id, Response::CreatedChangeset(result));
 
-				self.send_message(Response::ClosedChangeset(result));
+				let Some(changeset) = changeset else { return; };
+
+				// an empty changeset is still closed when the upload was cancelled
+				let result = if cancel.is_cancelled() {
+					Err(OsmApiError::Cancelled)
+				} else {
+					osmchange.prepare_upload(changeset.get().into());
+					client.upload_changeset(changeset, &osmchange).await
+				};
+				let conflict = match &result {
+					Err(OsmApiError::Conflict(conflict)) => Some(conflict.clone()),
+					_ => None,
+				};
+				self.send_message(id, Response::UploadedChangeset(result));
+
+				if let Some(conflict) = conflict {
+					let data = client.get_element(&conflict.element).await;
+					self.send_message(id, Response::Conflict(conflict, data));
+				}
+
+				// the changeset is closed even if the upload failed
+				let result = client.close_changeset(changeset).await;
+				self.send_message(id, Response::ClosedChangeset(result));
 			}
 		}
 	}
 
 	#[cfg(not(target_family = "wasm"))]
-	fn handle_message(&mut self, request: Request) {
+	fn handle_message(&self, id: RequestId, request: Request, cancel: &CancelFlag) {
+		let client = self.client();
 		match request {
-			Request::GetMap(bbox) => {
-				let data = self.osm_client.get_map(&bbox);
-				self.send_message(Response::Map(data));
+			Request::Authorize => {
+				match PendingAuthorization::start(&client.target_server) {
+					Ok(pending) => {
+						self.send_message(id, Response::AuthorizeUrl(pending.url.clone()));
+
+						// blocks only the thread of this request
+						self.send_message(id, Response::AuthCode(pending.wait(AUTHORIZATION_TIMEOUT)));
+					}
+					Err(err) => self.send_message(id, Response::AuthCode(Err(err))),
+				}
+			}
+			Request::GetMap(tiles) => {
+				let queue = Mutex::new(tiles.into_iter().collect::<VecDeque<_>>());
+				let error = Mutex::new(None);
+				let next = || queue.lock().unwrap().pop_front();
+
+				std::thread::scope(|scope| {
+					for _ in 0..MAX_CONCURRENT_DOWNLOADS {
+						scope.spawn(|| {
+							while !cancel.is_cancelled() && let Some(tile) = next() {
+								let result = client.get_tile(tile,
+									|err, delay| self.send_message(id, Response::MapRetry(err.clone(), delay)),
+									|progress| self.send_message(id, Response::MapProgress(progress)),
+								);
+								if let Err(err) = &result {
+									error.lock().unwrap().get_or_insert_with(|| err.clone());
+								}
+								self.send_message(id, Response::MapTile(tile, result));
+							}
+						});
+					}
+				});
+
+				self.send_message(id, Response::Map(download_result(error.into_inner().unwrap(), cancel)));
+			}
+			Request::Cancel(target) => {
+				self.cancel(target);
 			}
 			Request::SetTargetServer(target) => {
-				self.osm_client.target_server = target;
+				self.osm_client.lock().unwrap().set_target_server(target);
 			}
 			Request::FetchToken(auth_code) => {
-				let token = self.osm_client.fetch_token(auth_code);
-				let target_server = self.osm_client.target_server;
-
-				if let Ok(token) = token.as_ref() {
-					self.osm_client.auth_token[target_server as usize] = Some(token.to_owned());
+				let account = client.fetch_token(&auth_code)
+					.and_then(|token| client.get_user_details(&token).map(|user| Account { user, token }));
+				self.add_account(id, client.target_server, account);
+			}
+			Request::ValidateAccounts => {
+				for account in accounts(&client) {
+					if cancel.is_cancelled() { break; }
+					let result = client.get_user_details(&account.token);
+					self.account_validated(id, &client.target_server, account.user.id, result);
 				}
-
-				self.send_message(Response::Token(token, target_server));
 			}
-			Request::CreateChangeset(tags) => {
-				let result = self.osm_client.create_changeset(tags);
-				self.send_message(Response::CreatedChangeset(result));
+			Request::SetActiveAccount(user_id) => {
+				self.set_active_account(user_id);
 			}
-			Request::CloseChangeset(id) => {
-				let result = self.osm_client.close_changeset(id);
-				self.send_message(Response::ClosedChangeset(result));
+			Request::Logout(user_id) => {
+				let result = match self.remove_account(&client.target_server, user_id) {
+					Some(account) => client.revoke_token(&account.token),
+					None => Ok(()),
+				};
+				self.send_message(id, Response::LoggedOut(result, client.target_server));
+			}
+			Request::UploadChangeset(tags, mut osmchange) => {
+				let result = client.create_changeset(tags);
+				let changeset = result.as_ref().ok().copied();
+				self.send_message(id, Response::CreatedChangeset(result));
+
+				let Some(changeset) = changeset else { return; };
+
+				// an empty changeset is still closed when the upload was cancelled
+				let result = if cancel.is_cancelled() {
+					Err(OsmApiError::Cancelled)
+				} else {
+					osmchange.prepare_upload(changeset.get().into());
+					client.upload_changeset(changeset, &osmchange)
+				};
+				let conflict = match &result {
+					Err(OsmApiError::Conflict(conflict)) => Some(conflict.clone()),
+					_ => None,
+				};
+				self.send_message(id, Response::UploadedChangeset(result));
+
+				if let Some(conflict) = conflict {
+					let data = client.get_element(&conflict.element);
+					self.send_message(id, Response::Conflict(conflict, data));
+				}
+
+				// the changeset is closed even if the upload failed
+				let result = client.close_changeset(changeset);
+				self.send_message(id, Response::ClosedChangeset(result));
 			}
 		}
 	}
 
 	#[cfg(target_family = "wasm")]
 	#[allow(clippy::future_not_send)]
-	pub async fn run(&mut self, mut receiver: Receiver<Request>) {
-		while let Some(msg) = receiver.next().await {
-			self.handle_message(msg).await;
+	pub async fn run(self, mut receiver: Receiver<(RequestId, Request)>) {
+		while let Some((id, request)) = receiver.next().await {
+			if request.is_state_change() {
+				self.handle_message(id, request, &CancelFlag::default()).await;
+				continue;
+			}
+
+			let worker = self.clone();
+			let cancel = self.start(id);
+			wasm_bindgen_futures::spawn_local(async move {
+				worker.handle_message(id, request, &cancel).await;
+				worker.finish(id);
+			});
 		}
 	}
 
+	// Returns once the handle was dropped and all running requests are done.
 	#[cfg(not(target_family = "wasm"))]
-	pub fn run(&mut self, receiver: Receiver<Request>) {
-		for msg in receiver {
-			self.handle_message(msg);
+	pub fn run(&self, receiver: Receiver<(RequestId, Request)>) {
+		std::thread::scope(|scope| {
+			for (id, request) in receiver {
+				if request.is_state_change() {
+					self.handle_message(id, request, &CancelFlag::default());
+					continue;
+				}
+
+				let cancel = self.start(id);
+				scope.spawn(move || {
+					self.handle_message(id, request, &cancel);
+					self.finish(id);
+				});
+			}
+		});
+	}
+}
+
+#[cfg(all(test, not(target_family = "wasm")))]
+mod tests {
+	use super::*;
+	use crate::app::editor::cache::{Change, Element};
+	use crate::app::osm::mock::{self, MockOsmApi, MockResponse};
+	use crate::app::osm::Bbox;
+	use osm_parser::{Coordinate, Tags};
+
+	fn worker(mock: &MockOsmApi) -> (Worker, Receiver<(RequestId, Response)>) {
+		let (sender, receiver) = crossbeam_channel::unbounded();
+		(Worker::new(mock.client(), sender), receiver)
+	}
+
+	fn mock() -> MockOsmApi {
+		let mock = MockOsmApi::start();
+		for id in [1, 2] {
+			mock.add_element(Element::Node(osm_parser::Node { id, pos: Coordinate::new(50.0, 10.0), tags: Tags::default(), version: 1, changeset: 1 }));
 		}
+		mock.add_element(Element::Way(osm_parser::Way { id: 10, nodes: vec![1, 2], tags: Tags::default(), version: 1, changeset: 1 }));
+		mock
+	}
+
+	// Handles the request on the current thread, without a running loop.
+	fn handle(worker: &Worker, request: Request) {
+		worker.handle_message(0, request, &CancelFlag::default());
+	}
+
+	fn responses(receiver: &Receiver<(RequestId, Response)>) -> Vec<Response> {
+		receiver.try_iter().map(|(_, x)| x).collect()
+	}
+
+	fn upload_request(version: u32) -> Request {
+		let mut tags = Tags::default();
+		tags.insert("highway".into(), "footway".into());
+		let way = osm_parser::Way { id: 10, nodes: vec![1, 2], tags, version, changeset: 1 };
+		let prev = osm_parser::Way { tags: Tags::default(), ..way.clone() };
+		let tags = vec![Tag { k: "comment".into(), v: "Add footway".into() }];
+		Request::UploadChangeset(tags, Box::new(OsmChange::from(&vec![Change::UpdateWay(prev, way)])))
+	}
+
+	#[test]
+	fn get_map() {
+		let mock = mock();
+		let (worker, receiver) = worker(&mock);
+		let (sender, requests) = crossbeam_channel::unbounded();
+
+		let thread = std::thread::spawn(move || worker.run(requests));
+		let tiles = Tile::covering(&Bbox { left: 9.99, bottom: 49.99, right: 10.01, top: 50.01 });
+		sender.send((7, Request::GetMap(tiles))).unwrap();
+		drop(sender);
+		thread.join().unwrap();
+
+		let responses = receiver.try_iter().collect::<Vec<_>>();
+		assert!(responses.iter().all(|(id, _)| *id == 7));
+		let responses = responses.into_iter().map(|(_, x)| x).collect::<Vec<_>>();
+
+		let tiles = responses.iter().filter_map(|x| match x {
+			Response::MapTile(_, result) => Some(result.as_ref().unwrap()),
+			_ => None,
+		}).collect::<Vec<_>>();
+		assert_eq!(tiles.len(), 4);
+		assert!(tiles.iter().all(|data| data.ways.contains_key(&10)));
+		assert_eq!(responses.iter().filter(|x| matches!(x, Response::MapProgress(MapProgress { elements: 3, .. }))).count(), 4);
+		assert!(matches!(responses.last(), Some(Response::Map(Ok(())))), "{responses:?}");
+		assert_eq!(mock.requests().len(), 4);
+	}
+
+	#[test]
+	fn cancel_download() {
+		let mock = mock();
+		let (worker, receiver) = worker(&mock);
+
+		let cancel = CancelFlag::default();
+		cancel.cancel();
+		worker.handle_message(0, Request::GetMap(Tile::covering(&Bbox { left: 9.9, bottom: 49.9, right: 10.1, top: 50.1 })), &cancel);
+
+		let responses = responses(&receiver);
+		assert!(matches!(responses.as_slice(), [Response::Map(Err(OsmApiError::Cancelled))]), "{responses:?}");
+		assert!(mock.requests().is_empty());
+	}
+
+	#[test]
+	fn concurrent_requests() {
+		let mock = mock();
+		let (worker, receiver) = worker(&mock);
+		let (sender, requests) = crossbeam_channel::unbounded();
+		let thread = std::thread::spawn(move || worker.run(requests));
+
+		// the download waits for the rate limit to pass
+		mock.queue_response("GET", "/api/0.6/map", MockResponse::new(429, "").header("retry-after", "1"));
+		let tiles = Tile::covering(&Bbox { left: 10.0, bottom: 50.0, right: 10.01, top: 50.01 });
+		sender.send((1, Request::GetMap(tiles))).unwrap();
+		sender.send((2, Request::ValidateAccounts)).unwrap();
+
+		// which does not block other requests
+		let (id, response) = receiver.iter().find(|(_, x)| !matches!(x, Response::MapRetry(..))).unwrap();
+		assert!(matches!((id, response), (2, Response::AccountValidated(mock::USER_ID, Ok(_), _))));
+
+		sender.send((3, Request::Cancel(1))).unwrap();
+		drop(sender);
+		thread.join().unwrap();
+
+		let responses = receiver.try_iter().collect::<Vec<_>>();
+		assert!(matches!(responses.last(), Some((1, Response::Map(Err(OsmApiError::Cancelled))))), "{responses:?}");
+		assert!(responses.iter().all(|(id, _)| *id == 1));
+	}
+
+	#[test]
+	fn upload_changeset() {
+		let mock = mock();
+		let (worker, receiver) = worker(&mock);
+		handle(&worker, upload_request(1));
+
+		let responses = responses(&receiver);
+		assert!(matches!(responses.as_slice(), [
+			Response::CreatedChangeset(Ok(id)),
+			Response::UploadedChangeset(Ok(_)),
+			Response::ClosedChangeset(Ok(closed)),
+		] if id == closed), "{responses:?}");
+
+		// the changeset id is filled in before the upload
+		let upload = &mock.requests()[1];
+		assert_eq!(upload.path, "/api/0.6/changeset/1/upload");
+		assert!(upload.body.contains(r#"<way id="10" changeset="1" version="1">"#), "{}", upload.body);
+		assert!(upload.body.contains(r#"<tag k="highway" v="footway"/>"#));
+	}
+
+	#[test]
+	fn cancel_upload() {
+		let mock = mock();
+		let (worker, receiver) = worker(&mock);
+
+		let cancel = CancelFlag::default();
+		cancel.cancel();
+		worker.handle_message(0, upload_request(1), &cancel);
+
+		// the changeset was already created, so it is closed again
+		let responses = responses(&receiver);
+		assert!(matches!(responses.as_slice(), [
+			Response::CreatedChangeset(Ok(_)),
+			Response::UploadedChangeset(Err(OsmApiError::Cancelled)),
+			Response::ClosedChangeset(Ok(_)),
+		]), "{responses:?}");
+		assert!(mock.requests().iter().all(|x| !x.path.ends_with("/upload")));
+	}
+
+	#[test]
+	fn switch_account() {
+		let mock = mock();
+		let (worker, receiver) = worker(&mock);
+		let base_url = mock.target_server().base_url();
+
+		// the mock server accepts a single token, requests of the other account are rejected
+		let mut import = worker.client().active_account().unwrap().clone();
+		import.user = UserDetails { id: 2, display_name: "import".into() };
+		import.token.access_token = "import-token".into();
+		worker.osm_client.lock().unwrap().accounts.get_mut(&base_url).unwrap().insert(import);
+
+		handle(&worker, upload_request(1));
+		assert!(matches!(receiver.try_recv(), Ok((_, Response::CreatedChangeset(Err(OsmApiError::AuthExpired))))));
+		assert!(mock.requests()[0].header("authorization").unwrap().ends_with("import-token"));
+
+		handle(&worker, Request::SetActiveAccount(mock::USER_ID));
+		handle(&worker, upload_request(1));
+		assert!(matches!(receiver.try_recv(), Ok((_, Response::CreatedChangeset(Ok(_))))));
+
+		// validation removes the account with the rejected token
+		receiver.try_iter().for_each(drop);
+		handle(&worker, Request::ValidateAccounts);
+		let responses = responses(&receiver);
+		assert!(matches!(responses.as_slice(), [
+			Response::AccountValidated(mock::USER_ID, Ok(_), _),
+			Response::AccountValidated(2, Err(OsmApiError::AuthExpired), _),
+		]), "{responses:?}");
+		assert_eq!(worker.client().accounts[&base_url].list.len(), 1);
+	}
+
+	#[test]
+	fn upload_conflict() {
+		let mock = mock();
+		mock.add_element(Element::Way(osm_parser::Way { id: 10, nodes: vec![2, 1], tags: Tags::default(), version: 2, changeset: 2 }));
+		let (worker, receiver) = worker(&mock);
+		handle(&worker, upload_request(1));
+
+		let responses = responses(&receiver);
+		let [
+			Response::CreatedChangeset(Ok(_)),
+			Response::UploadedChangeset(Err(OsmApiError::Conflict(_))),
+			Response::Conflict(conflict, Ok(data)),
+			Response::ClosedChangeset(Ok(_)),
+		] = responses.as_slice() else {
+			panic!("unexpected responses: {responses:?}");
+		};
+
+		assert_eq!(conflict.server_version, 2);
+		assert_eq!(data.ways.get(&10).unwrap().nodes, vec![2, 1]);
+		assert_eq!(mock.requests()[2].path, "/api/0.6/way/10/full");
 	}
 }
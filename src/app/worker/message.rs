@@ -0,0 +1,118 @@
+// Requests and responses crossing the boundary of the Web Worker are encoded as MessagePack,
+// so each of them can be transferred as a single buffer instead of being cloned by the browser.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+pub fn encode(value: &impl Serialize) -> Vec<u8> {
+	// field names are kept, skipped optional fields are then filled in when decoding
+	rmp_serde::to_vec_named(value).expect("failed to encode worker message")
+}
+
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> T {
+	rmp_serde::from_slice(bytes).expect("failed to decode worker message")
+}
+
+// OsmData has no serde support, its elements are sent as tuples instead.
+pub mod osm_data {
+	use crate::app::osm::{OsmApiError, OsmResult};
+	use osm_parser::{Coordinate, Id, OsmData, Tags};
+	use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+	type NodeTuple<T> = (Id, f64, f64, Vec<(T, T)>, u32, u64); // id, lat, lon, tags, version, changeset
+	type WayTuple<T> = (Id, Vec<Id>, Vec<(T, T)>, u32, u64); // id, nodes, tags, version, changeset
+
+	fn tag_pairs(tags: &Tags) -> Vec<(&str, &str)> {
+		tags.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect()
+	}
+
+	fn tags(pairs: Vec<(String, String)>) -> Tags {
+		let mut tags = Tags::default();
+		for (k, v) in pairs {
+			tags.insert(k, v);
+		}
+		tags
+	}
+
+	#[allow(clippy::type_complexity)]
+	pub fn serialize<S: Serializer>(data: &OsmResult<OsmData>, serializer: S) -> Result<S::Ok, S::Error> {
+		let elements: Result<(Vec<NodeTuple<&str>>, Vec<WayTuple<&str>>), &OsmApiError> = data.as_ref().map(|data| (
+			data.nodes.values().map(|n| (n.id, n.pos.lat, n.pos.lon, tag_pairs(&n.tags), n.version, n.changeset)).collect(),
+			data.ways.values().map(|w| (w.id, w.nodes.clone(), tag_pairs(&w.tags), w.version, w.changeset)).collect(),
+		));
+		elements.serialize(serializer)
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<OsmResult<OsmData>, D::Error> {
+		let elements = OsmResult::<(Vec<NodeTuple<String>>, Vec<WayTuple<String>>)>::deserialize(deserializer)?;
+		Ok(elements.map(|(nodes, ways)| {
+			let mut data = OsmData::default();
+			for (id, lat, lon, pairs, version, changeset) in nodes {
+				data.nodes.insert(id, osm_parser::Node { id, pos: Coordinate::new(lat, lon), tags: tags(pairs), version, changeset });
+			}
+			for (id, nodes, pairs, version, changeset) in ways {
+				data.ways.insert(id, osm_parser::Way { id, nodes, tags: tags(pairs), version, changeset });
+			}
+			data
+		}))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::app::editor::cache::ElementId;
+	use crate::app::osm::{OsmApiError, Tile, VersionConflict};
+	use crate::app::osmchange::{Delete, OsmChange};
+	use crate::app::worker::{Request, Response};
+	use osm_parser::{Coordinate, OsmData, Tags};
+	use std::time::Duration;
+
+	fn round_trip<T: Serialize + DeserializeOwned>(value: &T) -> T {
+		decode(&encode(value))
+	}
+
+	#[test]
+	#[allow(clippy::float_cmp)]
+	fn map_tile() {
+		let mut tags = Tags::default();
+		tags.insert("name".into(), "Ünïcödé".into());
+
+		let mut data = OsmData::default();
+		data.nodes.insert(1, osm_parser::Node { id: 1, pos: Coordinate::new(-33.868_82, 151.209_29), tags: tags.clone(), version: 3, changeset: 7 });
+		data.ways.insert(2, osm_parser::Way { id: 2, nodes: vec![1, 1], tags, version: 1, changeset: 8 });
+
+		let Response::MapTile(tile, Ok(data)) = round_trip(&Response::MapTile(Tile { x: -3, y: 2500 }, Ok(data))) else { panic!("wrong response"); };
+		assert_eq!(tile, Tile { x: -3, y: 2500 });
+
+		let node = &data.nodes[&1];
+		assert_eq!((node.pos.lat, node.pos.lon), (-33.868_82, 151.209_29));
+		assert_eq!((node.version, node.changeset), (3, 7));
+		assert_eq!(node.tags.get("name").map(String::as_str), Some("Ünïcödé"));
+
+		let way = &data.ways[&2];
+		assert_eq!(way.nodes, [1, 1]);
+		assert_eq!((way.version, way.changeset), (1, 8));
+		assert_eq!(way.tags.get("name").map(String::as_str), Some("Ünïcödé"));
+	}
+
+	#[test]
+	fn errors() {
+		let retry = Response::MapRetry(OsmApiError::RateLimited { status: 429, retry_after: Some(Duration::from_secs(3)) }, Duration::from_secs(3));
+		assert!(matches!(round_trip(&retry), Response::MapRetry(OsmApiError::RateLimited { status: 429, retry_after: Some(x) }, _) if x.as_secs() == 3));
+
+		let conflict = VersionConflict { element: ElementId::Way(5), local_version: 1, server_version: 2 };
+		let response = round_trip(&Response::Conflict(conflict, Err(OsmApiError::Cancelled)));
+		assert!(matches!(response, Response::Conflict(VersionConflict { element: ElementId::Way(5), .. }, Err(OsmApiError::Cancelled))));
+	}
+
+	#[test]
+	fn upload() {
+		let mut osmchange = OsmChange::default();
+		osmchange.delete = Some(Delete { if_unused: None, ..Default::default() });
+
+		// the skipped if-unused attribute of the XML is missing from the message as well
+		let Request::UploadChangeset(_, decoded) = round_trip(&Request::UploadChangeset(vec![], Box::new(osmchange))) else { panic!("wrong request"); };
+		assert!(decoded.delete.is_some_and(|x| x.if_unused.is_none()));
+	}
+}
@@ -0,0 +1,106 @@
+// The worker runs in a dedicated Web Worker, which loads the same wasm module as the page (see assets/worker.js).
+// Both sides keep using the channels of the worker, messages are forwarded between them with postMessage.
+
+use super::message::{decode, encode};
+use super::{Request, RequestId, Response, Worker, WorkerHandle};
+use crate::app::osm::{Accounts, OsmClient, TargetServer};
+use futures::channel::mpsc::{self, UnboundedSender};
+use futures::stream::StreamExt;
+use js_sys::{Array, Uint8Array};
+use std::collections::HashMap;
+use wasm_bindgen::prelude::*;
+use web_sys::{DedicatedWorkerGlobalScope, MessageEvent, WorkerOptions, WorkerType};
+
+const WORKER_SCRIPT: &str = "./worker.js";
+
+// Messages from the page to the Web Worker, which has no access to the storage of the app.
+#[derive(serde::Serialize, serde::Deserialize)]
+enum ToWorker {
+	Start(TargetServer, HashMap<String, Accounts>),
+	Request(RequestId, Request),
+}
+
+// The buffer is moved to the other side instead of being copied, so it has to be sent as its own array.
+fn transferable(message: &impl serde::Serialize) -> (Uint8Array, Array) {
+	let array = Uint8Array::from(encode(message).as_slice());
+	let transfer = Array::of1(&array.buffer());
+	(array, transfer)
+}
+
+fn received<T: serde::de::DeserializeOwned>(event: &MessageEvent) -> T {
+	decode(&Uint8Array::new(&event.data()).to_vec())
+}
+
+pub fn spawn(osm_client: &OsmClient) -> WorkerHandle {
+	let (request_sender, mut request_receiver) = mpsc::unbounded::<(RequestId, Request)>();
+	let (response_sender, response_receiver) = mpsc::unbounded();
+
+	let options = WorkerOptions::new();
+	options.set_type(WorkerType::Module);
+	let worker = web_sys::Worker::new_with_options(WORKER_SCRIPT, &options).expect("failed to start the web worker");
+
+	let onmessage = Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+		let _ = response_sender.unbounded_send(received::<(RequestId, Response)>(&event));
+	});
+	worker.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+	onmessage.forget(); // the worker lives as long as the page
+
+	let post = move |message: &ToWorker| {
+		let (array, transfer) = transferable(message);
+		worker.post_message_with_transfer(&array, &transfer).expect("failed to post message to the web worker");
+	};
+
+	// sent before any request, messages are received in order
+	post(&ToWorker::Start(osm_client.target_server.clone(), osm_client.accounts.clone()));
+
+	wasm_bindgen_futures::spawn_local(async move {
+		while let Some((id, request)) = request_receiver.next().await {
+			post(&ToWorker::Request(id, request));
+		}
+	});
+
+	WorkerHandle {
+		sender: request_sender,
+		receiver: response_receiver,
+		next_id: 0,
+	}
+}
+
+// Entry point of the Web Worker, called by assets/worker.js once the module is initialized.
+#[wasm_bindgen]
+pub fn worker_main() {
+	let scope = js_sys::global().unchecked_into::<DedicatedWorkerGlobalScope>();
+	let mut requests = None;
+
+	let onmessage = Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+		match received(&event) {
+			ToWorker::Start(target_server, accounts) => requests = Some(start(target_server, accounts)),
+			ToWorker::Request(id, request) => {
+				if let Some(requests) = &requests {
+					let _ = requests.unbounded_send((id, request));
+				}
+			}
+		}
+	});
+	scope.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+	onmessage.forget();
+}
+
+fn start(target_server: TargetServer, accounts: HashMap<String, Accounts>) -> UnboundedSender<(RequestId, Request)> {
+	let mut osm_client = OsmClient::new(target_server);
+	osm_client.accounts = accounts;
+
+	let (request_sender, request_receiver) = mpsc::unbounded();
+	let (response_sender, mut response_receiver) = mpsc::unbounded::<(RequestId, Response)>();
+
+	wasm_bindgen_futures::spawn_local(Worker::new(osm_client, response_sender).run(request_receiver));
+	wasm_bindgen_futures::spawn_local(async move {
+		let scope = js_sys::global().unchecked_into::<DedicatedWorkerGlobalScope>();
+		while let Some(response) = response_receiver.next().await {
+			let (array, transfer) = transferable(&response);
+			scope.post_message_with_transfer(&array, &transfer).expect("failed to post message to the page");
+		}
+	});
+
+	request_sender
+}
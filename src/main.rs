@@ -45,6 +45,11 @@ pub fn set_update_flag(value: bool) {
 fn main() {
 	use eframe::wasm_bindgen::JsCast as _;
 
+	// the module is loaded by the Web Worker of the app as well, which starts on its own (see assets/worker.js)
+	if web_sys::window().is_none() {
+		return;
+	}
+
 	eframe::WebLogger::init(log::LevelFilter::Info).unwrap();
 
 	wasm_bindgen_futures::spawn_local(async {